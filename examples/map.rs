@@ -19,6 +19,8 @@ fn main() -> eframe::Result {
 struct App {
     tile_cache: Option<egui_3d_map_view::maps::TileCache>,
     camera: three_d::Camera,
+    /// f64 position of `camera`, see `egui_3d_map_view::maps::rtc_camera`.
+    eye: glam::DVec3,
     light: three_d::AmbientLight,
     key: String,
 }
@@ -43,6 +45,7 @@ impl App {
             three_d::AmbientLight::new(&context, 0.5, three_d::Srgba::WHITE);
         Self {
             tile_cache: None,
+            eye: egui_3d_map_view::maps::three_d_vec3_to_glam_d(&camera.position()),
             camera,
             light,
             key: "".to_string(),
@@ -59,6 +62,7 @@ impl eframe::App for App {
             egui_3d_map_view::threed_view::get_or_insert_context(ctx, frame.gl().unwrap());
 
         let target = self.camera.target();
+        let before = self.camera.position();
         egui_3d_map_view::orbitcontrol::handle_events(
             &mut self.camera,
             ctx,
@@ -66,6 +70,8 @@ impl eframe::App for App {
             6_378_000.0 - 15_000.,
             50_000_000.0, &mut three_d::Vector2::zero()
         );
+        self.eye =
+            egui_3d_map_view::orbitcontrol::follow_orbit(self.eye, before, self.camera.position());
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.label(format!("FPS: {:.1}", fps));
@@ -93,7 +99,7 @@ impl eframe::App for App {
                     self.camera.set_viewport(viewport);
                     if let Some(tile_cache) = &mut self.tile_cache {
                         tile_cache.load(&context);
-                        tile_cache.render(&self.camera, &[&self.light], false);
+                        tile_cache.render(&self.camera, self.eye, &[&self.light], false);
                     }
                 },
             );
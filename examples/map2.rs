@@ -19,6 +19,8 @@ use three_d::{EuclideanSpace, InnerSpace, MetricSpace, SquareMatrix, Zero};
 struct App {
     tile_cache: Option<egui_3d_map_view::maps::TileCache>,
     camera: three_d::Camera,
+    /// Position of `camera` in f64, see `egui_3d_map_view::maps::rtc_camera`.
+    eye: glam::DVec3,
     light: three_d::AmbientLight,
     key: String,
     view: egui_3d_map_view::threed_view::View,
@@ -32,6 +34,32 @@ struct App {
     gpx_routes: Vec<egui_3d_map_view::gpx::GpxRouteGPU>,
     m: three_d::ColorMaterial,
     rotation : three_d::Vec2,
+    disk_cache: std::sync::Arc<egui_3d_map_view::maps::DiskCache>,
+    terrain_url: String,
+    raster_url: String,
+    /// Camera of the last rendered frame and its eye, for picking.
+    render_camera: Option<(three_d::Camera, glam::DVec3)>,
+    /// Position clicked last.
+    picked: Option<egui_3d_map_view::threed_view::PickResult>,
+}
+
+fn create_tile_cache(
+    context: &three_d::Context,
+    key: String,
+    disk_cache: &std::sync::Arc<egui_3d_map_view::maps::DiskCache>,
+) -> egui_3d_map_view::maps::TileCache {
+    let source = egui_3d_map_view::maps::RestClient::new(key).with_disk_cache(disk_cache.clone());
+    tile_cache_with_picking(context, std::sync::Arc::new(source))
+}
+
+/// Tile cache of `source` that keeps the triangles for the CPU picking fallback.
+fn tile_cache_with_picking(
+    context: &three_d::Context,
+    source: std::sync::Arc<dyn egui_3d_map_view::maps::TileSource>,
+) -> egui_3d_map_view::maps::TileCache {
+    let mut tile_cache = egui_3d_map_view::maps::TileCache::with_source(context, source);
+    tile_cache.settings.pick_meshes = true;
+    tile_cache
 }
 
 impl App {
@@ -49,11 +77,12 @@ impl App {
 
         let light: three_d::AmbientLight =
             three_d::AmbientLight::new(&context, 0.5, three_d::Srgba::WHITE);
+        let disk_cache = std::sync::Arc::new(egui_3d_map_view::maps::DiskCache::new(
+            "tile_cache",
+            2_000_000_000,
+        ));
         let tile_cache = if key != "" {
-            Some(egui_3d_map_view::maps::TileCache::new(
-                &context,
-                key.clone(),
-            ))
+            Some(create_tile_cache(&context, key.clone(), &disk_cache))
         } else {
             None
         };
@@ -67,6 +96,7 @@ impl App {
         );
         Self {
             tile_cache,
+            eye: egui_3d_map_view::maps::three_d_vec3_to_glam_d(&camera.position()),
             camera,
             light,
             key,
@@ -81,6 +111,11 @@ impl App {
             gpx_routes: vec![],
             m,
             rotation : three_d::Vector2::zero(),
+            disk_cache,
+            terrain_url: String::new(),
+            raster_url: "https://tile.openstreetmap.org/{z}/{x}/{y}.png".into(),
+            render_camera: None,
+            picked: None,
         }
     }
 
@@ -94,12 +129,45 @@ impl App {
                 .show(ui);
             if key != self.key {
                 self.key = key;
-                self.tile_cache = Some(egui_3d_map_view::maps::TileCache::new(
+                self.tile_cache = Some(create_tile_cache(
                     &self.context,
                     self.key.clone(),
+                    &self.disk_cache,
                 ));
             }
         });
+        ui.horizontal(|ui| {
+            ui.label("or quantized-mesh terrain ");
+            ui.text_edit_singleline(&mut self.terrain_url);
+            if ui.button("load").clicked() && !self.terrain_url.is_empty() {
+                let source = egui_3d_map_view::maps::QuantizedMeshSource::new(self.terrain_url.clone())
+                    .with_disk_cache(self.disk_cache.clone());
+                self.tile_cache = Some(tile_cache_with_picking(
+                    &self.context,
+                    std::sync::Arc::new(source),
+                ));
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("or raster map ");
+            ui.text_edit_singleline(&mut self.raster_url);
+            if ui.button("load").clicked() && !self.raster_url.is_empty() {
+                let layer = egui_3d_map_view::maps::RasterLayer::Xyz(self.raster_url.clone());
+                // the OSM tile usage policy asks for an identifying user agent, caching and
+                // few parallel requests
+                let source = egui_3d_map_view::maps::RasterSource::new(layer)
+                    .with_disk_cache(self.disk_cache.clone())
+                    .with_attribution("© OpenStreetMap contributors")
+                    .with_header(
+                        "User-Agent",
+                        concat!("egui-3d-map-view/", env!("CARGO_PKG_VERSION"), " map2 example"),
+                    );
+                let mut tile_cache =
+                    tile_cache_with_picking(&self.context, std::sync::Arc::new(source));
+                tile_cache.settings.max_requests = 2;
+                self.tile_cache = Some(tile_cache);
+            }
+        });
     }
 }
 
@@ -170,6 +238,7 @@ impl eframe::App for App {
                     let resp = ui.interact(rect, ui.next_auto_id(), egui::Sense::all());
                     if resp.contains_pointer() {
                         let target = self.camera.target();
+                        let before = self.camera.position();
                         egui_3d_map_view::orbitcontrol::handle_events(
                             &mut self.camera,
                             ctx,
@@ -178,7 +247,13 @@ impl eframe::App for App {
                             max_distance,
                             &mut self.rotation,
                         );
+                        self.eye = egui_3d_map_view::orbitcontrol::follow_orbit(
+                            self.eye,
+                            before,
+                            self.camera.position(),
+                        );
                     }
+                    let depth_mode = self.view.depth_mode;
                     self.view.render(
                         &self.context,
                         rect.size(),
@@ -214,21 +289,73 @@ impl eframe::App for App {
                             //     three_d::Mat4::look_to_rh(three_d::Point3::from_vec(cam.position()), dir, cam.up());
 
                             cam.set_viewport(viewport);
+                            egui_3d_map_view::maps::set_auto_near_far(&mut cam, self.eye, depth_mode);
                             if let Some(tile_cache) = &mut self.tile_cache {
+                                tile_cache.settings.depth_mode = depth_mode;
                                 tile_cache.load(&self.context);
-                                tile_cache.render(&cam, &[&self.light], self.show_bounding_boxes);
+                                tile_cache.render(
+                                    &cam,
+                                    self.eye,
+                                    &[&self.light],
+                                    self.show_bounding_boxes,
+                                );
                             }
                             for route in self.gpx_routes.iter() {
-                                three_d::Geometry::render_with_material(
+                                egui_3d_map_view::threed_view::render_with_depth_mode(
                                     &route.mesh,
                                     &self.m,
-                                    &cam,
+                                    depth_mode,
+                                    &egui_3d_map_view::maps::rtc_camera(
+                                        &cam,
+                                        self.eye,
+                                        route.mesh.origin,
+                                    ),
                                     &[&self.light],
                                 );
                             }
+                            self.render_camera = Some((cam, self.eye));
                         },
                     );
                     self.view.show(ui);
+                    if let (Some((camera, eye)), Some(tile_cache)) =
+                        (&self.render_camera, &self.tile_cache)
+                    {
+                        let hovered = resp.hover_pos().and_then(|p| {
+                            let pos = (p - rect.min).to_pos2();
+                            self.view
+                                .pick(&self.context, camera, *eye, pos)
+                                .or_else(|| tile_cache.pick(camera, *eye, pos))
+                        });
+                        if resp.clicked() {
+                            self.picked = hovered;
+                        }
+                        let text = [hovered, self.picked]
+                            .iter()
+                            .zip(["", "clicked "])
+                            .filter_map(|(p, label)| {
+                                let p = p.as_ref()?;
+                                Some(format!(
+                                    "{label}{:.6}, {:.6}  {:.1} m",
+                                    p.lat, p.lon, p.height
+                                ))
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\n");
+                        ui.painter_at(rect).text(
+                            rect.left_bottom() + egui::vec2(4., -4.),
+                            egui::Align2::LEFT_BOTTOM,
+                            text,
+                            egui::FontId::monospace(11.),
+                            Color32::WHITE,
+                        );
+                    }
+                    if let Some(tile_cache) = &self.tile_cache {
+                        egui_3d_map_view::maps::show_attributions(
+                            ui,
+                            rect,
+                            &tile_cache.attributions(),
+                        );
+                    }
                 }
             });
 
@@ -255,6 +382,7 @@ impl eframe::App for App {
                                         let coodinates = egui_3d_map_view::maps::latlon_to_xyz(
                                             place.lat, place.lon, 1000.,
                                         );
+                                        self.eye = coodinates;
                                         self.camera = three_d::Camera::new_perspective(
                                             three_d::Viewport::new_at_origo(512, 512),
                                             egui_3d_map_view::maps::glam_d_vec3_to_three_d(
@@ -293,6 +421,100 @@ impl eframe::App for App {
                 ui.label(format!("FPS: {:.1}", fps));
 
                 ui.checkbox(&mut self.show_bounding_boxes, "show bounding boxes");
+                let mut log_depth =
+                    self.view.depth_mode == egui_3d_map_view::threed_view::DepthMode::Logarithmic;
+                if ui.checkbox(&mut log_depth, "logarithmic depth").changed() {
+                    self.view.depth_mode = if log_depth {
+                        egui_3d_map_view::threed_view::DepthMode::Logarithmic
+                    } else {
+                        egui_3d_map_view::threed_view::DepthMode::Standard
+                    };
+                }
+                let mut offline = self.disk_cache.is_offline();
+                if ui.checkbox(&mut offline, "offline").changed() {
+                    self.disk_cache.set_offline(offline);
+                }
+                ui.label(format!(
+                    "disk cache: {:.0} MB",
+                    self.disk_cache.size() as f64 / 1_000_000.
+                ));
+                if let Some(tile_cache) = &mut self.tile_cache {
+                    let settings = &mut tile_cache.settings;
+                    ui.add(
+                        egui::Slider::new(&mut settings.maximum_screen_space_error, 1.0..=64.0)
+                            .text("screen space error"),
+                    );
+                    ui.add(egui::Slider::new(&mut settings.max_level, 0..=30).text("max level"));
+                    ui.checkbox(&mut settings.adaptive, "adaptive detail");
+                    ui.horizontal(|ui| {
+                        use egui_3d_map_view::maps::TextureQuality;
+                        ui.label("textures:");
+                        ui.selectable_value(&mut settings.texture_quality, TextureQuality::Low, "low");
+                        ui.selectable_value(&mut settings.texture_quality, TextureQuality::Medium, "medium");
+                        ui.selectable_value(&mut settings.texture_quality, TextureQuality::High, "high");
+                    });
+                    if settings.adaptive {
+                        ui.label(format!(
+                            "current screen space error: {:.1}",
+                            tile_cache.screen_space_error
+                        ));
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("drape:");
+                        ui.text_edit_singleline(&mut self.raster_url);
+                        if ui.button("add").clicked() {
+                            let layer = egui_3d_map_view::maps::RasterLayer::Xyz(self.raster_url.clone());
+                            let source = egui_3d_map_view::maps::RasterSource::new(layer)
+                                .with_disk_cache(self.disk_cache.clone());
+                            tile_cache
+                                .overlays
+                                .push(egui_3d_map_view::maps::DrapeLayer::tiles(source));
+                        }
+                    });
+                    let mut raise = None;
+                    for (i, overlay) in tile_cache.overlays.iter_mut().enumerate() {
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut overlay.visible, format!("overlay {i}"));
+                            ui.add(egui::Slider::new(&mut overlay.opacity, 0.0..=1.0).text("opacity"));
+                            if i > 0 && ui.button("⬆").clicked() {
+                                raise = Some(i);
+                            }
+                        });
+                    }
+                    if let Some(i) = raise {
+                        tile_cache.overlays.swap(i, i - 1);
+                    }
+                    if let Some(error) = tile_cache.root_error() {
+                        ui.colored_label(Color32::RED, error.to_string());
+                    }
+                    let failures = tile_cache.failures();
+                    if !failures.is_empty() {
+                        ui.collapsing(format!("{} failed tiles", failures.len()), |ui| {
+                            for f in failures.iter().take(20) {
+                                ui.label(format!("{} ({}x): {}", f.uri, f.attempts, f.error));
+                            }
+                        });
+                    }
+                    let stats = tile_cache.stats();
+                    ui.label(format!(
+                        "tiles: {} known {} visible {} drawn",
+                        stats.tiles, stats.visible, stats.selected
+                    ));
+                    ui.label(format!(
+                        "loading: {} ({} queued, {} waiting for upload)",
+                        stats.loading, stats.queue_depth, stats.waiting_for_upload
+                    ));
+                    ui.label(format!(
+                        "downloaded: {:.0} MB, decode {:.1} ms/tile",
+                        stats.bytes_downloaded as f64 / 1_000_000.,
+                        stats.average_decode_time * 1000.
+                    ));
+                    ui.label(format!(
+                        "tile memory: {:.0} MB cpu {:.0} MB gpu",
+                        stats.cpu_bytes as f64 / 1_000_000.,
+                        stats.gpu_bytes as f64 / 1_000_000.
+                    ));
+                }
                 self.key_edit(ui);
 
                 let height = self.camera.target().distance(self.camera.position()) - min_distance;
@@ -310,7 +532,7 @@ impl eframe::App for App {
                 //         ui,
                 //         |builder| {
                 //             if let Some(tile_cache) = &mut self.tile_cache {
-                //                 calc_visiblity(tile_cache, &self.camera);
+                //                 calc_visiblity(tile_cache, &self.camera, self.eye);
                 //                 show_tile_tree(&tile_cache.roots, builder, tile_cache, 0);
                 //             }
                 //         },
@@ -323,8 +545,12 @@ impl eframe::App for App {
     }
 }
 
-fn calc_visiblity(tile_cache: &mut egui_3d_map_view::maps::TileCache, camera: &three_d::Camera) {
-    let s = egui_3d_map_view::maps::get_view_state(camera);
+fn calc_visiblity(
+    tile_cache: &mut egui_3d_map_view::maps::TileCache,
+    camera: &three_d::Camera,
+    eye: glam::DVec3,
+) {
+    let s = egui_3d_map_view::maps::get_view_state(camera, eye);
     for (_, t) in tile_cache.cache.iter_mut() {
         t.is_visible = t.bv.is_visible(s.position) && t.bv.intersects_frustum(&s.frustum);
         t.meets_sse = s.does_tile_meet_sse(t);
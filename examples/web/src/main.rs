@@ -52,6 +52,8 @@ use egui::Color32;
 struct App {
     tile_cache: Option<egui_3d_map_view::maps::TileCache>,
     camera: three_d::Camera,
+    /// f64 position of `camera`, see `egui_3d_map_view::maps::rtc_camera`.
+    eye: glam::DVec3,
     light: three_d::AmbientLight,
     key: String,
     view: egui_3d_map_view::threed_view::View,
@@ -88,6 +90,7 @@ impl App {
         };
         Self {
             tile_cache,
+            eye: egui_3d_map_view::maps::three_d_vec3_to_glam_d(&camera.position()),
             camera,
             light,
             key,
@@ -179,6 +182,7 @@ impl eframe::App for App {
                     let resp = ui.interact(rect, ui.next_auto_id(), egui::Sense::all());
                     if resp.contains_pointer() {
                         let target = self.camera.target();
+                        let before = self.camera.position();
                         egui_3d_map_view::orbitcontrol::handle_events(
                             &mut self.camera,
                             ctx,
@@ -186,6 +190,11 @@ impl eframe::App for App {
                             6_378_000.0 - 15_000.,
                             50_000_000.0,
                         );
+                        self.eye = egui_3d_map_view::orbitcontrol::follow_orbit(
+                            self.eye,
+                            before,
+                            self.camera.position(),
+                        );
                     }
                     self.view.render(
                         frame,
@@ -199,6 +208,7 @@ impl eframe::App for App {
                                 tile_cache.load(&self.context);
                                 tile_cache.render(
                                     &self.camera,
+                                    self.eye,
                                     &[&self.light],
                                     self.show_bounding_boxes,
                                 );
@@ -232,6 +242,7 @@ impl eframe::App for App {
                                         let coodinates = egui_3d_map_view::maps::latlon_to_xyz(
                                             place.lat, place.lon, 1000.,
                                         );
+                                        self.eye = coodinates;
                                         self.camera = three_d::Camera::new_perspective(
                                             three_d::Viewport::new_at_origo(512, 512),
                                             egui_3d_map_view::maps::glam_d_vec3_to_three_d(
@@ -267,7 +278,7 @@ impl eframe::App for App {
                 //         ui,
                 //         |builder| {
                 //             if let Some(tile_cache) = &mut self.tile_cache {
-                //                 calc_visiblity(tile_cache, &self.camera);
+                //                 calc_visiblity(tile_cache, &self.camera, self.eye);
                 //                 show_tile_tree(&tile_cache.roots, builder, tile_cache, 0);
                 //             }
                 //         },
@@ -280,8 +291,12 @@ impl eframe::App for App {
     }
 }
 
-fn calc_visiblity(tile_cache: &mut egui_3d_map_view::maps::TileCache, camera: &three_d::Camera) {
-    let s = egui_3d_map_view::maps::get_view_state(camera);
+fn calc_visiblity(
+    tile_cache: &mut egui_3d_map_view::maps::TileCache,
+    camera: &three_d::Camera,
+    eye: glam::DVec3,
+) {
+    let s = egui_3d_map_view::maps::get_view_state(camera, eye);
     for (_, t) in tile_cache.cache.iter_mut() {
         t.is_visible = t.bv.is_visible(s.position) && t.bv.intersects_frustum(&s.frustum);
         t.meets_sse = s.does_tile_meet_sse(t);
@@ -1,3 +1,8 @@
+#[cfg(not(target_arch = "wasm32"))]
+pub type BoxFuture<'a, T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send + 'a>>;
+#[cfg(target_arch = "wasm32")]
+pub type BoxFuture<'a, T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + 'a>>;
+
 #[cfg(not(target_arch = "wasm32"))]
 pub async fn fetch(request: &ehttp::Request) -> Result<ehttp::Response, String> {
     let response = ehttp::fetch_blocking(request)?;
@@ -41,7 +46,7 @@ pub async fn fetch(request: &ehttp::Request) -> Result<ehttp::Response, String>
 
     let result = ehttp::Response {
         url: request.url.clone(),
-        ok: true,
+        ok: resp.ok(),
         status: resp.status(),
         status_text: resp.status_text(),
         headers: ehttp::Headers::new(&[]),
@@ -52,11 +57,113 @@ pub async fn fetch(request: &ehttp::Request) -> Result<ehttp::Response, String>
 }
 
 
+/// Seconds since the unix epoch.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+#[cfg(target_arch = "wasm32")]
+pub fn now() -> f64 {
+    js_sys::Date::now() / 1000.
+}
+
+/// Tasks of [`execute`] and the threads running them.
+#[cfg(not(target_arch = "wasm32"))]
+struct WorkerPool {
+    tasks: std::collections::VecDeque<BoxFuture<'static, ()>>,
+    workers: usize,
+    idle: usize,
+    max_workers: usize,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+static WORKER_POOL: std::sync::Mutex<WorkerPool> = std::sync::Mutex::new(WorkerPool {
+    tasks: std::collections::VecDeque::new(),
+    workers: 0,
+    idle: 0,
+    max_workers: 4,
+});
+#[cfg(not(target_arch = "wasm32"))]
+static TASK_QUEUED: std::sync::Condvar = std::sync::Condvar::new();
+
+/// Runs `f` in the background. Natively the tasks share a pool of at most
+/// [`reserve_workers`] threads, later tasks wait for a free one.
 #[cfg(not(target_arch = "wasm32"))]
 pub fn execute<F: std::future::Future<Output = ()> + Send + 'static>(f: F) {
-    std::thread::spawn(move || futures::executor::block_on(f));
+    let mut pool = WORKER_POOL.lock().unwrap();
+    pool.tasks.push_back(Box::pin(f));
+    if pool.tasks.len() > pool.idle && pool.workers < pool.max_workers {
+        pool.workers += 1;
+        std::thread::spawn(run_worker);
+    } else {
+        TASK_QUEUED.notify_one();
+    }
 }
 #[cfg(target_arch = "wasm32")]
 pub fn execute<F: std::future::Future<Output = ()> + 'static>(f: F) {
     wasm_bindgen_futures::spawn_local(f);
-}
\ No newline at end of file
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn run_worker() {
+    loop {
+        let task = {
+            let mut pool = WORKER_POOL.lock().unwrap();
+            loop {
+                if let Some(task) = pool.tasks.pop_front() {
+                    break task;
+                }
+                pool.idle += 1;
+                pool = TASK_QUEUED.wait(pool).unwrap();
+                pool.idle -= 1;
+            }
+        };
+        futures::executor::block_on(task);
+    }
+}
+
+/// Lets the pool of [`execute`] grow to at least `count` threads, the [`crate::maps::TileCache`]
+/// reserves one per request it keeps in flight. Tasks run on the browser's event loop on
+/// the web.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn reserve_workers(count: usize) {
+    let mut pool = WORKER_POOL.lock().unwrap();
+    pool.max_workers = pool.max_workers.max(count);
+}
+#[cfg(target_arch = "wasm32")]
+pub fn reserve_workers(_count: usize) {}
+
+/// Shared flag a background task polls to stop early once its result is no longer wanted.
+///
+/// Cancelling does not abort a download that already started: natively [`fetch`] blocks
+/// until the response arrived and on the web the request gets no abort signal. Tasks check
+/// the token before downloading and again before decoding, so a cancelled download is
+/// never decoded. The task marks the token finished once it has stopped, so a download
+/// that was still running is accounted for after cancelling.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    finished: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Called by the task when it returns.
+    pub fn finish(&self) {
+        self.finished.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
@@ -22,6 +22,9 @@ pub struct LineMesh {
     pub transformation: Mat4,
     pub animation_transformation: Mat4,
     pub animation: Option<Box<dyn Fn(f32) -> Mat4 + Send + Sync>>,
+    /// World position the vertices are relative to. Render with the
+    /// [`crate::maps::rtc_camera`] of it, so meshes far away from the world origin do not jitter.
+    pub origin: glam::DVec3,
 }
 
 impl LineMesh {
@@ -34,14 +37,35 @@ impl LineMesh {
             transformation: Mat4::identity(),
             animation_transformation: Mat4::identity(),
             animation: None,
+            origin: glam::DVec3::ZERO,
         }
     }
 
+    /// `cpu_mesh` holds positions relative to `origin`.
+    pub fn new_with_origin(context: &Context, cpu_mesh: &CpuMesh, origin: glam::DVec3) -> Self {
+        let mut mesh = Self::new(context, cpu_mesh);
+        mesh.origin = origin;
+        mesh
+    }
+
     pub fn from_vector(context: &Context, vertices: Vec<Vec3>) -> Self {
         let mut cpu_mesh = CpuMesh::default();
         cpu_mesh.positions = Positions::F32(vertices);
         Self::new(context, &cpu_mesh)
     }
+
+    /// Lines between world positions, stored relative to their centre.
+    pub fn from_points(context: &Context, points: &[glam::DVec3]) -> Self {
+        let origin = points.iter().sum::<glam::DVec3>() / points.len().max(1) as f64;
+        let mut cpu_mesh = CpuMesh::default();
+        cpu_mesh.positions = Positions::F32(
+            points
+                .iter()
+                .map(|p| crate::maps::glam_d_vec3_to_three_d(&(*p - origin)))
+                .collect(),
+        );
+        Self::new_with_origin(context, &cpu_mesh, origin)
+    }
 }
 
 pub struct BaseMesh {
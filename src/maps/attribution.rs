@@ -0,0 +1,47 @@
+use super::*;
+
+/// Splits a glTF `asset.copyright` like `"Google;Data SIO, NOAA"` into its providers.
+pub fn split_copyright(copyright: &str) -> Vec<String> {
+    copyright
+        .split(';')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect()
+}
+
+impl TileCache {
+    /// Data providers of the tiles drawn in the last frame, most frequent first.
+    /// They have to be shown with the map, e.g. with [`show_attributions`].
+    pub fn attributions(&self) -> Vec<String> {
+        let mut counts: std::collections::HashMap<&str, usize> = Default::default();
+        for id in self.selection.selected.iter() {
+            if let Some(t) = self.cache.get(id) {
+                for c in t.copyright.iter() {
+                    *counts.entry(c.as_str()).or_default() += 1;
+                }
+            }
+        }
+        let mut attributions: Vec<_> = counts.into_iter().collect();
+        attributions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        attributions.into_iter().map(|x| x.0.to_string()).collect()
+    }
+}
+
+/// Draws `attributions` into the bottom right corner of `rect`, wrapped to its width.
+pub fn show_attributions(ui: &egui::Ui, rect: egui::Rect, attributions: &[String]) {
+    if attributions.is_empty() {
+        return;
+    }
+    let painter = ui.painter_at(rect);
+    let galley = painter.layout(
+        attributions.join("; "),
+        egui::FontId::proportional(11.),
+        egui::Color32::WHITE,
+        (rect.width() - 12.).max(0.),
+    );
+    let pos = rect.right_bottom() - galley.size() - egui::vec2(6., 6.);
+    let background = egui::Rect::from_min_size(pos, galley.size()).expand(3.);
+    painter.rect_filled(background, 3., egui::Color32::from_black_alpha(140));
+    painter.galley(pos, galley, egui::Color32::WHITE);
+}
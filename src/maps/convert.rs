@@ -60,4 +60,36 @@ pub fn three_d_vec3_to_glam(vec: &three_d::Vec3) -> glam::Vec3 {
 
 pub fn glam_d_vec3_to_three_d(vec: &glam::DVec3) -> three_d::Vec3 {
     three_d::Vec3::new(vec.x as f32, vec.y as f32, vec.z as f32)
-}
\ No newline at end of file
+}
+/// Copy of `camera` at `eye` with `origin` as world origin, for geometry stored relative to
+/// `origin`. [`three_d::Camera`] keeps its position in f32, which is decimetres off at earth
+/// scale, so the f64 `eye` is passed alongside and only the small offset is converted.
+pub fn rtc_camera(
+    camera: &three_d::Camera,
+    eye: glam::DVec3,
+    origin: glam::DVec3,
+) -> three_d::Camera {
+    let eye = glam_d_vec3_to_three_d(&(eye - origin));
+    let mut rtc = camera.clone();
+    rtc.set_view(eye, eye + camera.view_direction(), camera.up());
+    rtc
+}
+
+/// Sets the near and far plane of `camera` from its height above the earth, see
+/// [`crate::threed_view::DepthMode::near_far`]. The far plane reaches the horizon.
+/// `eye` is the f64 position of `camera`, see [`rtc_camera`].
+pub fn set_auto_near_far(
+    camera: &mut three_d::Camera,
+    eye: glam::DVec3,
+    depth_mode: crate::threed_view::DepthMode,
+) {
+    // polar radius, the height is underestimated rather than clipping terrain
+    const MIN_RADIUS: f64 = 6_356_752.;
+    let distance = eye.length();
+    let height = distance - MIN_RADIUS;
+    let horizon = (distance * distance - MIN_RADIUS * MIN_RADIUS).max(0.).sqrt();
+    let (near, far) = depth_mode.near_far(height as f32, horizon as f32);
+    if let three_d::ProjectionType::Perspective { field_of_view_y } = *camera.projection_type() {
+        camera.set_perspective_projection(field_of_view_y, near, far);
+    }
+}
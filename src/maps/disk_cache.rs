@@ -0,0 +1,349 @@
+use super::TileSourceError;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Content-addressed on-disk cache for tileset nodes and tile contents.
+///
+/// Entries are keyed by the request url without the `key` and `session` query
+/// parameters, so they survive api key changes and session renewals. Once the
+/// cache grows beyond `max_bytes` the least recently used entries are removed.
+/// On the web there is no file system, every lookup is a miss.
+pub struct DiskCache {
+    pub dir: std::path::PathBuf,
+    pub max_bytes: u64,
+    offline: AtomicBool,
+    index: std::sync::Mutex<DiskCacheIndex>,
+}
+
+#[derive(Default)]
+struct DiskCacheIndex {
+    entries: std::collections::HashMap<String, DiskCacheEntry>,
+    total_bytes: u64,
+    clock: u64,
+}
+
+struct DiskCacheEntry {
+    size: u64,
+    last_access: u64,
+}
+
+/// Removes the parameters that only authenticate a request from `url`.
+pub fn cache_key(url: &reqwest::Url) -> String {
+    let mut url = url.clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|x| x.0 != "key" && x.0 != "session")
+        .map(|x| (x.0.to_string(), x.1.to_string()))
+        .collect();
+    if pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url.to_string()
+}
+
+/// FNV-1a, stable across platforms and compiler versions.
+fn hash(key: &str) -> String {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in key.bytes() {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    format!("{h:016x}")
+}
+
+impl DiskCache {
+    pub fn new(dir: impl Into<std::path::PathBuf>, max_bytes: u64) -> Self {
+        let s = Self {
+            dir: dir.into(),
+            max_bytes,
+            offline: AtomicBool::new(false),
+            index: Default::default(),
+        };
+        s.scan();
+        return s;
+    }
+
+    /// In offline mode requests are served from the cache only and never reach the network.
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::Relaxed);
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.index.lock().unwrap().total_bytes
+    }
+
+    fn path(&self, name: &str) -> std::path::PathBuf {
+        self.dir.join(name)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn scan(&self) {
+        let _ = std::fs::create_dir_all(&self.dir);
+        let Ok(dir) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut files = vec![];
+        for entry in dir.flatten() {
+            if let Ok(meta) = entry.metadata() {
+                if meta.is_file() {
+                    let modified = meta.modified().unwrap_or(std::time::UNIX_EPOCH);
+                    files.push((entry.file_name().to_string_lossy().to_string(), meta.len(), modified));
+                }
+            }
+        }
+        // the modification time doubles as last access time across sessions
+        files.sort_by_key(|x| x.2);
+        let mut index = self.index.lock().unwrap();
+        for (name, size, _) in files {
+            index.clock += 1;
+            index.total_bytes += size;
+            let last_access = index.clock;
+            index.entries.insert(name, DiskCacheEntry { size, last_access });
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn scan(&self) {}
+
+    /// Each file starts with the length-prefixed key, which guards against hash collisions.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let name = hash(key);
+        if !self.index.lock().unwrap().entries.contains_key(&name) {
+            return None;
+        }
+        let path = self.path(&name);
+        let bytes = std::fs::read(&path).ok()?;
+        let key_len = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?) as usize;
+        if bytes.get(4..4 + key_len)? != key.as_bytes() {
+            return None;
+        }
+
+        if let Ok(file) = std::fs::File::options().write(true).open(&path) {
+            let _ = file.set_modified(std::time::SystemTime::now());
+        }
+        let mut index = self.index.lock().unwrap();
+        index.clock += 1;
+        let clock = index.clock;
+        if let Some(e) = index.entries.get_mut(&name) {
+            e.last_access = clock;
+        }
+        Some(bytes[4 + key_len..].to_vec())
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn get(&self, _key: &str) -> Option<Vec<u8>> {
+        None
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn put(&self, key: &str, data: &[u8]) {
+        let name = hash(key);
+        let mut bytes = Vec::with_capacity(4 + key.len() + data.len());
+        bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(data);
+        if std::fs::write(self.path(&name), &bytes).is_err() {
+            return;
+        }
+
+        let mut index = self.index.lock().unwrap();
+        index.clock += 1;
+        let entry = DiskCacheEntry {
+            size: bytes.len() as u64,
+            last_access: index.clock,
+        };
+        index.total_bytes += entry.size;
+        if let Some(old) = index.entries.insert(name, entry) {
+            index.total_bytes -= old.size;
+        }
+        self.evict(&mut index);
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn put(&self, _key: &str, _data: &[u8]) {}
+
+    /// Removes the least recently used entries until the cache is below 90% of `max_bytes`.
+    fn evict(&self, index: &mut DiskCacheIndex) {
+        if index.total_bytes <= self.max_bytes {
+            return;
+        }
+        let target = self.max_bytes / 10 * 9;
+        let mut entries: Vec<_> = index
+            .entries
+            .iter()
+            .map(|(name, e)| (e.last_access, name.clone()))
+            .collect();
+        entries.sort();
+        for (_, name) in entries {
+            if index.total_bytes <= target {
+                break;
+            }
+            if let Some(e) = index.entries.remove(&name) {
+                index.total_bytes -= e.size;
+                let _ = std::fs::remove_file(self.path(&name));
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut index = self.index.lock().unwrap();
+        for (name, _) in index.entries.drain() {
+            let _ = std::fs::remove_file(self.path(&name));
+        }
+        index.total_bytes = 0;
+    }
+}
+
+/// Fetches `url`, going through `cache` if there is one.
+pub async fn fetch_cached(
+    cache: Option<&DiskCache>,
+    url: reqwest::Url,
+) -> Result<Vec<u8>, TileSourceError> {
+    fetch_cached_with_headers(cache, url, &[]).await
+}
+
+/// [`fetch_cached`] sending `headers` with the request. They are not part of the cache key.
+pub async fn fetch_cached_with_headers(
+    cache: Option<&DiskCache>,
+    url: reqwest::Url,
+    headers: &[(&str, &str)],
+) -> Result<Vec<u8>, TileSourceError> {
+    let key = cache_key(&url);
+    if let Some(cache) = cache {
+        if let Some(bytes) = cache.get(&key) {
+            return Ok(bytes);
+        }
+        if cache.is_offline() {
+            return Err(TileSourceError::Offline(key));
+        }
+    }
+
+    let mut request = ehttp::Request::get(url);
+    for (name, value) in headers {
+        request.headers.insert(*name, *value);
+    }
+    let res = crate::http::fetch(&request).await?;
+    if !res.ok {
+        let body: String = res.text().unwrap_or_default().chars().take(200).collect();
+        return Err(TileSourceError::Http {
+            status: res.status,
+            message: format!("{} {key} {body}", res.status_text),
+        });
+    }
+    if let Some(cache) = cache {
+        cache.put(&key, &res.bytes);
+    }
+    Ok(res.bytes)
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    /// Empty directory for one test, removed again when dropped.
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("egui-3d-map-view-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn cache_key_strips_key_and_session() {
+        let url = |s: &str| reqwest::Url::parse(s).unwrap();
+        assert_eq!(
+            cache_key(&url("https://tile.googleapis.com/v1/3dtiles/root.json?key=abc")),
+            "https://tile.googleapis.com/v1/3dtiles/root.json"
+        );
+        assert_eq!(
+            cache_key(&url("https://example.com/a.glb?session=s1&v=2&key=k")),
+            "https://example.com/a.glb?v=2"
+        );
+        assert_eq!(
+            cache_key(&url("https://example.com/a.glb?session=s1")),
+            cache_key(&url("https://example.com/a.glb?session=s2")),
+        );
+    }
+
+    #[test]
+    fn entries_survive_a_restart() {
+        let dir = TempDir::new("restart");
+        let cache = DiskCache::new(&dir.0, 1 << 20);
+        cache.put("a", b"first");
+        assert_eq!(cache.get("a").as_deref(), Some(&b"first"[..]));
+        assert_eq!(cache.get("b"), None);
+
+        let cache = DiskCache::new(&dir.0, 1 << 20);
+        assert_eq!(cache.size(), 4 + 1 + 5);
+        assert_eq!(cache.get("a").as_deref(), Some(&b"first"[..]));
+    }
+
+    #[test]
+    fn key_header_guards_against_collisions() {
+        let dir = TempDir::new("collision");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        // a file under the name of "b" that was written for the key "a"
+        let mut bytes = 1u32.to_le_bytes().to_vec();
+        bytes.extend_from_slice(b"a");
+        bytes.extend_from_slice(b"data of a");
+        std::fs::write(dir.0.join(hash("b")), bytes).unwrap();
+
+        let cache = DiskCache::new(&dir.0, 1 << 20);
+        assert_eq!(cache.get("b"), None);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_below_90_percent() {
+        let dir = TempDir::new("evict");
+        let cache = DiskCache::new(&dir.0, 1000);
+        let data = [7u8; 300];
+        // 306 bytes per entry with the key header
+        for key in ["k1", "k2", "k3"] {
+            cache.put(key, &data);
+        }
+        assert_eq!(cache.size(), 918);
+        assert!(cache.get("k1").is_some());
+
+        cache.put("k4", &data);
+        assert!(cache.size() <= 900);
+        assert_eq!(cache.size(), 612);
+        assert!(cache.get("k2").is_none());
+        assert!(cache.get("k3").is_none());
+        assert!(!dir.0.join(hash("k2")).exists());
+        assert!(cache.get("k1").is_some());
+        assert!(cache.get("k4").is_some());
+    }
+
+    #[test]
+    fn offline_mode_only_serves_cached_entries() {
+        let dir = TempDir::new("offline");
+        let cache = DiskCache::new(&dir.0, 1 << 20);
+        let cached = reqwest::Url::parse("https://example.invalid/a.glb?key=abc").unwrap();
+        let missing = reqwest::Url::parse("https://example.invalid/b.glb?key=abc").unwrap();
+        cache.put(&cache_key(&cached), b"cached");
+        cache.set_offline(true);
+
+        let fetch = |url| futures::executor::block_on(fetch_cached(Some(&cache), url));
+        assert_eq!(fetch(cached), Ok(b"cached".to_vec()));
+        assert_eq!(
+            fetch(missing),
+            Err(TileSourceError::Offline("https://example.invalid/b.glb".into()))
+        );
+    }
+}
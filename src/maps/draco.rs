@@ -0,0 +1,105 @@
+use super::*;
+
+const DRACO_EXTENSION: &str = "KHR_draco_mesh_compression";
+/// `draco::DataType::DT_FLOAT32`
+const DRACO_FLOAT32: u32 = 9;
+
+/// Indices, positions and uvs of a primitive.
+pub struct PrimitiveGeometry {
+    pub indices: Vec<u32>,
+    pub positions: Vec<three_d::Vec3>,
+    pub uvs: Vec<three_d::Vec2>,
+}
+
+/// Draco attribute id of a glTF attribute, as listed in the extension of the primitive.
+fn draco_attribute(extension: &serde_json::Value, name: &str) -> Option<u32> {
+    extension.get("attributes")?.get(name)?.as_u64().map(|x| x as u32)
+}
+
+/// Reads the geometry of `prim` with the uvs of the set `tex_coord`,
+/// decoding it with Draco if the primitive is compressed.
+pub async fn read_geometry(
+    prim: &gltf::Primitive<'_>,
+    tex_coord: u32,
+    doc: &gltf::Document,
+    buffer_data: &Vec<gltf::buffer::Data>,
+) -> Result<PrimitiveGeometry, String> {
+    if let Some(extension) = prim.extension_value(DRACO_EXTENSION) {
+        return decode_draco(prim, extension, tex_coord, doc, buffer_data).await;
+    }
+
+    let reader = prim.reader(|buffer| Some(&buffer_data[buffer.index()]));
+    let positions: Vec<_> = reader
+        .read_positions()
+        .ok_or("Primitive has no POSITION attribute")?
+        .map(|v| three_d::vec3(v[0], v[1], v[2]))
+        .collect();
+    let indices: Vec<_> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..positions.len() as u32).collect(),
+    };
+    let uvs: Vec<_> = reader
+        .read_tex_coords(tex_coord)
+        .map(|tc| {
+            // Automatically converts normalized ints to f32
+            tc.into_f32()
+                .map(|uv| three_d::vec2(uv[0], uv[1]))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(PrimitiveGeometry {
+        indices,
+        positions,
+        uvs,
+    })
+}
+
+async fn decode_draco(
+    prim: &gltf::Primitive<'_>,
+    extension: &serde_json::Value,
+    tex_coord: u32,
+    doc: &gltf::Document,
+    buffer_data: &Vec<gltf::buffer::Data>,
+) -> Result<PrimitiveGeometry, String> {
+    let position = draco_attribute(extension, "POSITION")
+        .ok_or("draco primitive has no POSITION attribute")?;
+    let mut attributes = vec![draco_gltf_rs::AttrInfo {
+        unique_id: position,
+        dim: 3,
+        data_type: DRACO_FLOAT32,
+    }];
+    let uv = draco_attribute(extension, &format!("TEXCOORD_{tex_coord}"));
+    if let Some(uv) = uv {
+        attributes.push(draco_gltf_rs::AttrInfo {
+            unique_id: uv,
+            dim: 2,
+            data_type: DRACO_FLOAT32,
+        });
+    }
+
+    let decoded = draco_gltf_rs::decode_draco(prim, doc, buffer_data, &attributes)
+        .await
+        .map_err(|x| format!("draco: {x}"))?;
+
+    // the attributes are returned in the order they were requested
+    let positions = decoded
+        .attributes
+        .first()
+        .ok_or("draco: POSITION was not decoded")?
+        .chunks_exact(3)
+        .map(|v| three_d::vec3(v[0], v[1], v[2]))
+        .collect();
+    let uvs = match uv {
+        Some(_) => decoded
+            .attributes
+            .get(1)
+            .map(|a| a.chunks_exact(2).map(|v| three_d::vec2(v[0], v[1])).collect())
+            .unwrap_or_default(),
+        None => vec![],
+    };
+    Ok(PrimitiveGeometry {
+        indices: decoded.indices.iter().map(|i| *i as u32).collect(),
+        positions,
+        uvs,
+    })
+}
@@ -0,0 +1,788 @@
+use super::*;
+use crate::threed_view::DepthMode;
+
+/// Most overlay images sampled when drawing one tile, the topmost ones are kept.
+pub const MAX_DRAPES: usize = 8;
+
+/// Geodetic coordinates of each fragment relative to the tile, see [`DrapeFrame`].
+const DRAPE_SHADER: &str = "
+in vec3 pos;
+uniform mat3 drapeRotation;
+uniform vec3 drapeOrigin;
+uniform float drapeLat0;
+
+// (latitude, longitude) in radians relative to the tile
+vec2 drape_delta() {
+    vec3 q = drapeOrigin + drapeRotation * pos;
+    float rho = length(q.xy);
+    // Bowring's formula for the WGS84 ellipsoid
+    const float A = 6378137.0;
+    const float B = 6356752.314245;
+    const float E2 = 0.00669437999014;
+    const float EP2 = 0.00673949674228;
+    float theta = atan(q.z * A, rho * B);
+    float s = sin(theta);
+    float c = cos(theta);
+    float lat = atan(q.z + EP2 * B * s * s * s, rho - E2 * A * c * c * c);
+    return vec2(lat - drapeLat0, atan(q.y, q.x));
+}
+
+// web mercator y in radians relative to the tile
+float drape_mercator_delta(float dlat) {
+    // small steps are lost in the difference of two large values
+    if (abs(dlat) < 0.01) {
+        return dlat / cos(drapeLat0 + 0.5 * dlat);
+    }
+    float lat = clamp(drapeLat0 + dlat, -1.4844, 1.4844);
+    return log(tan(0.7853981634 + 0.5 * lat)) - log(tan(0.7853981634 + 0.5 * drapeLat0));
+}
+
+vec4 drape_sample(sampler2D tex, vec4 uv, vec4 clip, float mercator, vec2 delta) {
+    float dv = mercator > 0.5 ? drape_mercator_delta(delta.x) : delta.x;
+    vec2 t = uv.xy + vec2(delta.y * uv.z, -dv * uv.w);
+    if (t.x < clip.x || t.x > clip.z || t.y < clip.y || t.y > clip.w) {
+        return vec4(0.0);
+    }
+    return texture(tex, t);
+}
+";
+
+/// How the rows of a [`DrapeImage`] map to latitudes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DrapeProjection {
+    /// Rows are linear in latitude (EPSG:4326).
+    Geographic,
+    /// Rows are linear in web mercator y (EPSG:3857), like slippy map tiles.
+    WebMercator,
+}
+
+/// `bounds` (`[west, south, east, north]` in degrees) with `east` moved past `west` when the
+/// range crosses the antimeridian, i.e. `west > east`.
+fn unwrap_bounds(bounds: [f64; 4]) -> [f64; 4] {
+    let [west, south, east, north] = bounds;
+    let east = if east < west { east + 360. } else { east };
+    [west, south, east, north]
+}
+
+/// Whether two `[west, south, east, north]` rects in degrees overlap, either may cross the
+/// antimeridian or extend beyond ±180°.
+fn bounds_intersect(a: [f64; 4], b: [f64; 4]) -> bool {
+    let [a_west, a_south, a_east, a_north] = unwrap_bounds(a);
+    let [b_west, b_south, b_east, b_north] = unwrap_bounds(b);
+    if b_south > a_north || a_south > b_north {
+        return false;
+    }
+    let (a_width, b_width) = (a_east - a_west, b_east - b_west);
+    if a_width + b_width >= 360. {
+        return true;
+    }
+    // start of `b` east of the start of `a`
+    let d = (b_west - a_west).rem_euclid(360.);
+    d <= a_width || d + b_width >= 360.
+}
+
+/// Web mercator y of `lat` (degrees), 0 at the north edge and 1 at the south edge of the map.
+fn mercator_y(lat: f64) -> f64 {
+    let lat = lat.clamp(-85.0511, 85.0511).to_radians();
+    (1. - (lat.tan() + 1. / lat.cos()).ln() / std::f64::consts::PI) / 2.
+}
+
+/// Reference point of a tile for the per fragment projection of [`DrapeImage`]s in f32.
+///
+/// Positions relative to the tile centre are rotated so the centre lies at longitude 0,
+/// which keeps the longitude differences exact. Latitudes are taken relative to the one
+/// of the centre, the texture coordinates are computed in f64 at the centre and only the
+/// differences are added on the GPU.
+pub struct DrapeFrame {
+    rotation: glam::DMat3,
+    origin: DVec3,
+    /// Radians, rounded to f32 like on the GPU.
+    lat0: f64,
+    /// Radians.
+    lon0: f64,
+}
+
+impl DrapeFrame {
+    pub fn new(origin: DVec3) -> Self {
+        let lon0 = origin.y.atan2(origin.x);
+        // the latitude of a point near the earth centre is meaningless, any reference works
+        let lat0 = if origin.length() > 1_000_000. {
+            xyz_to_latlonele(origin).0.to_radians()
+        } else {
+            0.
+        };
+        let rotation = glam::DMat3::from_rotation_z(-lon0);
+        Self {
+            rotation,
+            origin: rotation * origin,
+            lat0: lat0 as f32 as f64,
+            lon0,
+        }
+    }
+
+    fn use_uniforms(&self, program: &three_d::Program) {
+        let c = self.rotation.to_cols_array().map(|x| x as f32);
+        program.use_uniform(
+            "drapeRotation",
+            three_d::Mat3::new(c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7], c[8]),
+        );
+        program.use_uniform("drapeOrigin", glam_d_vec3_to_three_d(&self.origin));
+        program.use_uniform("drapeLat0", self.lat0 as f32);
+    }
+}
+
+/// Georeferenced image draped over the tiles.
+pub struct DrapeImage {
+    pub texture: three_d::Texture2DRef,
+    /// `[west, south, east, north]` in degrees, `west > east` crosses the antimeridian.
+    pub bounds: [f64; 4],
+    pub projection: DrapeProjection,
+}
+
+impl DrapeImage {
+    pub fn new(
+        ctx3d: &three_d::Context,
+        texture: &three_d::CpuTexture,
+        bounds: [f64; 4],
+        projection: DrapeProjection,
+    ) -> Self {
+        Self {
+            texture: three_d::Texture2DRef::from_cpu_texture(ctx3d, texture),
+            bounds,
+            projection,
+        }
+    }
+
+    /// Texture coordinates of `(lat, lon)` in degrees.
+    pub fn uv(&self, lat: f64, lon: f64) -> glam::DVec2 {
+        let [west, south, east, north] = unwrap_bounds(self.bounds);
+        let u = (lon - west) / (east - west);
+        let v = match self.projection {
+            DrapeProjection::Geographic => (north - lat) / (north - south),
+            DrapeProjection::WebMercator => {
+                (mercator_y(lat) - mercator_y(north)) / (mercator_y(south) - mercator_y(north))
+            }
+        };
+        glam::dvec2(u, v)
+    }
+
+    pub fn intersects(&self, bounds: [f64; 4]) -> bool {
+        bounds_intersect(self.bounds, bounds)
+    }
+
+    /// Samples this image within `clip` (`[u_min, v_min, u_max, v_max]`) on a tile at `frame`.
+    fn drape(&self, frame: &DrapeFrame, clip: [f64; 4], opacity: f32) -> Drape<'_> {
+        let [west, south, east, north] = unwrap_bounds(self.bounds);
+        let mut lon0 = frame.lon0.to_degrees();
+        // images may extend beyond the antimeridian
+        if lon0 < west - 180. {
+            lon0 += 360.;
+        } else if lon0 > east + 180. {
+            lon0 -= 360.;
+        }
+        let uv0 = self.uv(frame.lat0.to_degrees(), lon0);
+        let v_scale = match self.projection {
+            DrapeProjection::Geographic => 1. / (north - south).to_radians(),
+            DrapeProjection::WebMercator => {
+                1. / (std::f64::consts::TAU * (mercator_y(south) - mercator_y(north)))
+            }
+        };
+        let u_scale = 1. / (east - west).to_radians();
+        Drape {
+            texture: &self.texture,
+            uv: three_d::vec4(uv0.x as f32, uv0.y as f32, u_scale as f32, v_scale as f32),
+            clip: three_d::vec4(clip[0] as f32, clip[1] as f32, clip[2] as f32, clip[3] as f32),
+            mercator: self.projection == DrapeProjection::WebMercator,
+            opacity,
+        }
+    }
+}
+
+/// One image sampled by a [`TileMaterial`].
+pub struct Drape<'a> {
+    texture: &'a three_d::Texture2DRef,
+    /// Texture coordinates at the tile centre and their change per radian.
+    uv: three_d::Vec4,
+    clip: three_d::Vec4,
+    mercator: bool,
+    opacity: f32,
+}
+
+enum OverlayState {
+    Loading {
+        promise: poll_promise::Promise<Result<three_d::CpuTexture, String>>,
+        cancel: crate::http::CancelToken,
+        /// Failed attempts before this one.
+        attempts: u32,
+    },
+    Ready {
+        image: DrapeImage,
+        gpu_bytes: usize,
+    },
+    Failed {
+        attempts: u32,
+        retry_at: f64,
+    },
+}
+
+struct OverlayTile {
+    state: OverlayState,
+    last_used: u64,
+}
+
+/// Slippy map tiles draped at the level matching the size of each 3D tile.
+///
+/// The tiles are requested through the [`RequestScheduler`] of the [`TileCache`] and count
+/// towards its GPU budget.
+pub struct RasterOverlay {
+    pub source: Arc<RasterSource>,
+    tiles: std::collections::HashMap<[u32; 3], OverlayTile>,
+    /// Frame of the last draw, for the tiles started by the scheduler.
+    frame: u64,
+}
+
+impl RasterOverlay {
+    pub fn new(source: RasterSource) -> Self {
+        Self {
+            source: Arc::new(source),
+            tiles: Default::default(),
+            frame: 0,
+        }
+    }
+
+    /// Raster tiles covering `bounds`, at most 2x2 of them. Columns wrap around the
+    /// antimeridian.
+    fn covering(&self, bounds: [f64; 4]) -> Vec<[u32; 3]> {
+        let [west, south, east, north] = unwrap_bounds(bounds);
+        let width = (east - west).max(1e-9);
+        let mut z = ((360. / width).log2().floor().max(0.) as u32).min(self.source.max_zoom);
+        loop {
+            let n = 1i64 << z;
+            let size = n as f64;
+            let column = |lon: f64| ((lon + 180.) / 360. * size).floor() as i64;
+            let row = |lat: f64| (mercator_y(lat) * size).floor().clamp(0., size - 1.) as u32;
+            let (x0, y0, y1) = (column(west), row(north), row(south));
+            let x1 = column(east).clamp(x0, x0 + n - 1);
+            if (x1 - x0 + 1) * (y1 as i64 - y0 as i64 + 1) <= 4 || z == 0 {
+                let mut tiles = vec![];
+                for x in x0..=x1 {
+                    for y in y0..=y1 {
+                        tiles.push([z, x.rem_euclid(n) as u32, y]);
+                    }
+                }
+                return tiles;
+            }
+            z -= 1;
+        }
+    }
+
+    /// Whether `tile` has to be requested, failed tiles wait for their retry time.
+    pub(crate) fn is_due(&self, tile: [u32; 3], now: f64) -> bool {
+        match self.tiles.get(&tile).map(|t| &t.state) {
+            None => true,
+            Some(OverlayState::Failed { retry_at, .. }) => *retry_at <= now,
+            Some(_) => false,
+        }
+    }
+
+    /// Starts loading `tile`, called by the scheduler.
+    pub(crate) fn start(&mut self, tile: [u32; 3], counters: &Arc<TileLoadCounters>) {
+        let attempts = match self.tiles.get(&tile).map(|t| &t.state) {
+            Some(OverlayState::Failed { attempts, .. }) => *attempts,
+            _ => 0,
+        };
+        let cancel = crate::http::CancelToken::default();
+        let promise = get_overlay_tile(self.source.clone(), tile, cancel.clone(), counters.clone());
+        let state = OverlayState::Loading {
+            promise,
+            cancel,
+            attempts,
+        };
+        let last_used = self.frame;
+        self.tiles.insert(tile, OverlayTile { state, last_used });
+    }
+
+    pub(crate) fn in_flight(&self) -> usize {
+        self.tiles
+            .values()
+            .filter(|t| match &t.state {
+                OverlayState::Loading { promise, .. } => promise.ready().is_none(),
+                _ => false,
+            })
+            .count()
+    }
+
+    /// Ready tile standing in for `tile`, i.e. `tile` itself or its closest ready ancestor,
+    /// with the rect of `tile` in the texture coordinates of the returned one.
+    fn lookup(&mut self, tile: [u32; 3], frame: u64) -> Option<([u32; 3], [f64; 4])> {
+        if let Some(t) = self.tiles.get_mut(&tile) {
+            t.last_used = frame;
+        }
+        let mut key = tile;
+        loop {
+            if let Some(t) = self.tiles.get_mut(&key) {
+                if let OverlayState::Ready { .. } = t.state {
+                    t.last_used = frame;
+                    let scale = (1u64 << (tile[0] - key[0])) as f64;
+                    let u = tile[1] as f64 / scale - key[1] as f64;
+                    let v = tile[2] as f64 / scale - key[2] as f64;
+                    return Some((key, [u, v, u + 1. / scale, v + 1. / scale]));
+                }
+            }
+            if key[0] == 0 {
+                return None;
+            }
+            key = [key[0] - 1, key[1] / 2, key[2] / 2];
+        }
+    }
+
+    fn image(&self, tile: [u32; 3]) -> Option<&DrapeImage> {
+        match &self.tiles.get(&tile)?.state {
+            OverlayState::Ready { image, .. } => Some(image),
+            _ => None,
+        }
+    }
+
+    /// Uploads loaded tiles and schedules the retry of failed ones.
+    fn load(&mut self, ctx3d: &three_d::Context, settings: &TileCacheSettings) {
+        for ([z, x, y], t) in self.tiles.iter_mut() {
+            let OverlayState::Loading {
+                promise, attempts, ..
+            } = &mut t.state
+            else {
+                continue;
+            };
+            let next = match promise.ready_mut() {
+                Some(Ok(texture)) => {
+                    settings.texture_quality.apply(texture);
+                    let [west, south, east, north] = raster_tile_rect(*z, *x, *y);
+                    OverlayState::Ready {
+                        gpu_bytes: texture_gpu_bytes(texture),
+                        image: DrapeImage::new(
+                            ctx3d,
+                            texture,
+                            [west, south, east, north].map(|x| x.to_degrees()),
+                            DrapeProjection::WebMercator,
+                        ),
+                    }
+                }
+                Some(Err(_)) => OverlayState::Failed {
+                    attempts: *attempts + 1,
+                    retry_at: settings.retry_at(*attempts + 1),
+                },
+                None => continue,
+            };
+            t.state = next;
+        }
+    }
+
+    /// Cancels the requests of tiles not drawn for more than `max_age` frames.
+    fn cancel_stale(
+        &mut self,
+        frame: u64,
+        max_age: u64,
+        cancelled: &mut Vec<crate::http::CancelToken>,
+    ) {
+        self.tiles.retain(|_, t| {
+            let OverlayState::Loading { promise, cancel, .. } = &t.state else {
+                return true;
+            };
+            if promise.ready().is_some() || frame.saturating_sub(t.last_used) <= max_age {
+                return true;
+            }
+            cancel.cancel();
+            cancelled.push(cancel.clone());
+            false
+        });
+    }
+
+    /// GPU bytes of the uploaded tiles.
+    pub fn memory_usage(&self) -> usize {
+        self.tiles
+            .values()
+            .map(|t| match &t.state {
+                OverlayState::Ready { gpu_bytes, .. } => *gpu_bytes,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// `(last_used, tile)` of the uploaded tiles not drawn for more than `min_age` frames.
+    fn eviction_candidates(&self, frame: u64, min_age: u64) -> Vec<(u64, [u32; 3])> {
+        self.tiles
+            .iter()
+            .filter(|(_, t)| matches!(t.state, OverlayState::Ready { .. }))
+            .filter(|(_, t)| frame.saturating_sub(t.last_used) > min_age)
+            .map(|(key, t)| (t.last_used, *key))
+            .collect()
+    }
+
+    /// Releases `tile` and returns the GPU bytes it held.
+    fn evict(&mut self, tile: [u32; 3]) -> usize {
+        match self.tiles.remove(&tile).map(|t| t.state) {
+            Some(OverlayState::Ready { gpu_bytes, .. }) => gpu_bytes,
+            _ => 0,
+        }
+    }
+}
+
+fn get_overlay_tile(
+    source: Arc<RasterSource>,
+    [z, x, y]: [u32; 3],
+    cancel: crate::http::CancelToken,
+    counters: Arc<TileLoadCounters>,
+) -> poll_promise::Promise<Result<three_d::CpuTexture, String>> {
+    let (sender, promise) = poll_promise::Promise::new();
+    crate::http::execute(async move {
+        let texture = async {
+            if cancel.is_cancelled() {
+                return Err("cancelled".to_string());
+            }
+            let bytes = source
+                .download(&source.tile_uri(z, x, y))
+                .await
+                .map_err(|e| e.to_string())?;
+            counters.add_download(bytes.len());
+            if cancel.is_cancelled() {
+                return Err("cancelled".to_string());
+            }
+            decode_image(&bytes)
+        };
+        sender.send(texture.await);
+        cancel.finish();
+    });
+    promise
+}
+
+pub enum DrapeSource {
+    /// A single georeferenced image, e.g. a png with known corner coordinates.
+    Image(DrapeImage),
+    Tiles(RasterOverlay),
+}
+
+/// Raster layer projected onto the meshes of a [`TileCache`]. Layers are drawn in the order
+/// of [`TileCache::overlays`], later ones on top.
+pub struct DrapeLayer {
+    pub source: DrapeSource,
+    pub opacity: f32,
+    pub visible: bool,
+    /// Unique per layer, requests refer to it so reordering or removing layers is safe.
+    id: u64,
+}
+
+impl DrapeLayer {
+    pub fn new(source: DrapeSource) -> Self {
+        static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        Self {
+            source,
+            opacity: 1.,
+            visible: true,
+            id: NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Drapes a png, jpeg or webp image covering `bounds` (`[west, south, east, north]` in degrees).
+    pub fn image(
+        ctx3d: &three_d::Context,
+        bytes: &[u8],
+        bounds: [f64; 4],
+        projection: DrapeProjection,
+    ) -> Result<Self, String> {
+        let texture = decode_image(bytes)?;
+        Ok(Self::new(DrapeSource::Image(DrapeImage::new(
+            ctx3d, &texture, bounds, projection,
+        ))))
+    }
+
+    /// Drapes slippy map tiles, e.g. `RasterLayer::Xyz("https://example.com/{z}/{x}/{y}.png")`.
+    pub fn tiles(source: RasterSource) -> Self {
+        Self::new(DrapeSource::Tiles(RasterOverlay::new(source)))
+    }
+
+    pub fn load(&mut self, ctx3d: &three_d::Context, settings: &TileCacheSettings) {
+        if let DrapeSource::Tiles(overlay) = &mut self.source {
+            overlay.load(ctx3d, settings);
+        }
+    }
+}
+
+/// `[west, south, east, north]` in degrees around a tile, from its bounding box.
+fn tile_bounds(bv: &BoundingVolume) -> [f64; 4] {
+    if let Some([west, south, east, north, _, _]) = bv.region {
+        return [west, south, east, north].map(|x| x.to_degrees());
+    }
+    // boxes around large parts of the globe
+    if bv.center.length() < 1_000_000. {
+        return [-180., -90., 180., 90.];
+    }
+    let (lat, lon, _) = xyz_to_latlonele(bv.center);
+    let radius = (bv.x_axis.abs() + bv.y_axis.abs() + bv.z_axis.abs()).length();
+    let dlat = (radius / 6_356_752.).to_degrees();
+    let dlon = (dlat / lat.to_radians().cos().max(0.01)).min(180.);
+    [lon - dlon, (lat - dlat).max(-90.), lon + dlon, (lat + dlat).min(90.)]
+}
+
+/// Images of the visible `overlays` covering the tile `bv`, bottom layer first.
+///
+/// Missing raster tiles are requested from `scheduler` with `priority`. Their closest ready
+/// ancestor stands in, clipped to the rect of the missing tile, so it neither covers ready
+/// tiles nor is blended twice.
+pub(crate) fn collect_drapes<'a>(
+    overlays: &'a mut [DrapeLayer],
+    bv: &BoundingVolume,
+    drape_frame: &DrapeFrame,
+    frame: u64,
+    priority: f64,
+    scheduler: &mut RequestScheduler,
+) -> Vec<Drape<'a>> {
+    if overlays.iter().all(|o| !o.visible) {
+        return vec![];
+    }
+    let bounds = tile_bounds(bv);
+    let now = crate::http::now();
+
+    let mut tiles = vec![];
+    for (i, overlay) in overlays.iter_mut().enumerate() {
+        if !overlay.visible {
+            continue;
+        }
+        let layer = overlay.id;
+        let DrapeSource::Tiles(raster) = &mut overlay.source else {
+            continue;
+        };
+        raster.frame = frame;
+        for tile in raster.covering(bounds) {
+            if raster.is_due(tile, now) {
+                scheduler.request(TileRequest::Overlay { layer, tile }, priority);
+            }
+            if let Some((key, clip)) = raster.lookup(tile, frame) {
+                tiles.push((i, key, clip));
+            }
+        }
+    }
+
+    let overlays: &'a [DrapeLayer] = overlays;
+    let mut drapes = vec![];
+    for (layer, overlay) in overlays.iter().enumerate() {
+        if !overlay.visible {
+            continue;
+        }
+        match &overlay.source {
+            DrapeSource::Image(image) => {
+                if image.intersects(bounds) {
+                    drapes.push(image.drape(drape_frame, [0., 0., 1., 1.], overlay.opacity));
+                }
+            }
+            DrapeSource::Tiles(raster) => {
+                for (_, key, clip) in tiles.iter().filter(|x| x.0 == layer) {
+                    if let Some(image) = raster.image(*key) {
+                        drapes.push(image.drape(drape_frame, *clip, overlay.opacity));
+                    }
+                }
+            }
+        }
+    }
+    let skip = drapes.len().saturating_sub(MAX_DRAPES);
+    drapes.drain(..skip);
+    drapes
+}
+
+impl TileCache {
+    /// GPU bytes of the raster overlay tiles.
+    pub fn overlay_memory_usage(&self) -> usize {
+        self.overlays
+            .iter()
+            .map(|o| match &o.source {
+                DrapeSource::Tiles(raster) => raster.memory_usage(),
+                DrapeSource::Image(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Raster overlay tiles being loaded.
+    pub(crate) fn overlays_in_flight(overlays: &[DrapeLayer]) -> usize {
+        overlays
+            .iter()
+            .map(|o| match &o.source {
+                DrapeSource::Tiles(raster) => raster.in_flight(),
+                DrapeSource::Image(_) => 0,
+            })
+            .sum()
+    }
+
+    pub(crate) fn cancel_stale_overlays(&mut self) {
+        for overlay in self.overlays.iter_mut() {
+            if let DrapeSource::Tiles(raster) = &mut overlay.source {
+                raster.cancel_stale(
+                    self.frame,
+                    self.settings.cancel_after_frames,
+                    &mut self.scheduler.cancelled,
+                );
+            }
+        }
+    }
+
+    /// `(last_used, layer id, tile)` of the overlay tiles that may be evicted.
+    pub(crate) fn overlay_eviction_candidates(&self) -> Vec<(u64, u64, [u32; 3])> {
+        let mut candidates = vec![];
+        for overlay in self.overlays.iter() {
+            if let DrapeSource::Tiles(raster) = &overlay.source {
+                for (last_used, tile) in
+                    raster.eviction_candidates(self.frame, self.settings.eviction_min_age)
+                {
+                    candidates.push((last_used, overlay.id, tile));
+                }
+            }
+        }
+        candidates
+    }
+
+    /// Releases an overlay tile of the layer with the id `layer` and returns the GPU bytes
+    /// it held.
+    pub(crate) fn evict_overlay(&mut self, layer: u64, tile: [u32; 3]) -> usize {
+        match self.overlays.iter_mut().find(|o| o.id == layer).map(|o| &mut o.source) {
+            Some(DrapeSource::Tiles(raster)) => raster.evict(tile),
+            _ => 0,
+        }
+    }
+}
+
+/// Tile material with the [`DrapeLayer`]s sampled in the same pass, so the overlays cost
+/// no extra draw calls. Wraps the [`three_d::ColorMaterial`] of the tile and blends each
+/// [`Drape`] over its color. Also samples the [`CompressedTexture2D`] of the tile.
+pub struct TileMaterial<'a> {
+    pub base: &'a three_d::ColorMaterial,
+    /// Texture three-d cannot sample, used instead of the texture of `base`.
+    pub compressed: Option<&'a CompressedTexture2D>,
+    pub depth_mode: DepthMode,
+    pub frame: &'a DrapeFrame,
+    pub drapes: &'a [Drape<'a>],
+}
+
+impl three_d::Material for TileMaterial<'_> {
+    fn id(&self) -> three_d::EffectMaterialId {
+        let texture = self.base.texture.is_some() as u16;
+        let log_depth = (self.depth_mode == DepthMode::Logarithmic) as u16;
+        let compressed = self.compressed.is_some() as u16;
+        three_d::EffectMaterialId(
+            crate::threed_view::TILE_MATERIAL_ID
+                + texture
+                + 2 * log_depth
+                + 4 * compressed
+                + 8 * self.drapes.len() as u16,
+        )
+    }
+
+    fn fragment_shader_source(&self, lights: &[&dyn three_d::Light]) -> String {
+        // the color material brings `color_mapping` and its uniforms
+        let base = self.base.fragment_shader_source(lights);
+        let mut source = crate::threed_view::rename_main(&base, "tile_main");
+        if !self.drapes.is_empty() {
+            source.push_str(DRAPE_SHADER);
+        }
+        for i in 0..self.drapes.len() {
+            source.push_str(&format!(
+                "uniform sampler2D drapeTexture{i};
+uniform vec4 drapeUv{i};
+uniform vec4 drapeClip{i};
+uniform float drapeMercator{i};
+uniform float drapeOpacity{i};
+"
+            ));
+        }
+        if self.compressed.is_some() {
+            source.push_str("in vec2 uvs;\nuniform sampler2D compressedTexture;\n");
+        }
+        source.push_str("void main() {\n    tile_main();\n");
+        if self.compressed.is_some() {
+            // the texels are srgb like the output of `tile_main`, so they apply after its
+            // color mapping
+            source.push_str("    outColor *= texture(compressedTexture, uvs);\n");
+        }
+        if !self.drapes.is_empty() {
+            source.push_str("    vec2 delta = drape_delta();\n    vec4 drape;\n");
+        }
+        for i in 0..self.drapes.len() {
+            source.push_str(&format!(
+                "    drape = drape_sample(drapeTexture{i}, drapeUv{i}, drapeClip{i}, drapeMercator{i}, delta);
+    outColor.rgb = mix(outColor.rgb, color_mapping(drape.rgb), drape.a * drapeOpacity{i});
+"
+            ));
+        }
+        source.push_str("}\n");
+        match self.depth_mode {
+            DepthMode::Standard => source,
+            DepthMode::Logarithmic => crate::threed_view::with_log_depth(source),
+        }
+    }
+
+    fn use_uniforms(
+        &self,
+        program: &three_d::Program,
+        viewer: &dyn three_d::Viewer,
+        lights: &[&dyn three_d::Light],
+    ) {
+        self.base.use_uniforms(program, viewer, lights);
+        if let Some(texture) = self.compressed {
+            // three-d numbers the texture units of a program in the order its textures are
+            // first bound, the unit after the base texture and the drapes stays free
+            let unit = self.base.texture.is_some() as u32 + self.drapes.len() as u32;
+            texture.use_texture(program, "compressedTexture", unit);
+        }
+        if !self.drapes.is_empty() {
+            self.frame.use_uniforms(program);
+        }
+        for (i, d) in self.drapes.iter().enumerate() {
+            program.use_texture(&format!("drapeTexture{i}"), d.texture);
+            program.use_uniform(&format!("drapeUv{i}"), d.uv);
+            program.use_uniform(&format!("drapeClip{i}"), d.clip);
+            program.use_uniform(&format!("drapeMercator{i}"), d.mercator as u8 as f32);
+            program.use_uniform(&format!("drapeOpacity{i}"), d.opacity);
+        }
+        if self.depth_mode == DepthMode::Logarithmic {
+            program.use_uniform("logDepthFar", viewer.z_far());
+        }
+    }
+
+    fn render_states(&self) -> three_d::RenderStates {
+        self.base.render_states()
+    }
+
+    fn material_type(&self) -> three_d::MaterialType {
+        self.base.material_type()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covering_across_the_antimeridian() {
+        let overlay = RasterOverlay::new(RasterSource::new(RasterLayer::Xyz(String::new())));
+        // 2° wide region from 179°E to 179°W
+        let tiles = overlay.covering([179., 0., -179., 1.]);
+        assert_eq!(tiles, vec![[7, 127, 63], [7, 127, 64], [7, 0, 63], [7, 0, 64]]);
+        // boxes of tiles near the antimeridian extend beyond 180°
+        assert_eq!(overlay.covering([179., 0., 181., 1.]), tiles);
+        assert_eq!(overlay.covering([-180., -80., 180., 80.]), vec![[0, 0, 0]]);
+    }
+
+    #[test]
+    fn bounds_intersect_across_the_antimeridian() {
+        let wrapped = [170., -10., -170., 10.];
+        assert!(bounds_intersect(wrapped, [175., 0., 176., 1.]));
+        assert!(bounds_intersect(wrapped, [-175., 0., -174., 1.]));
+        assert!(bounds_intersect(wrapped, [179., 0., -179., 1.]));
+        assert!(bounds_intersect(wrapped, [185., 0., 186., 1.]));
+        assert!(!bounds_intersect(wrapped, [0., 0., 1., 1.]));
+        assert!(!bounds_intersect(wrapped, [175., 20., 176., 21.]));
+        assert!(bounds_intersect([-180., -90., -170., 90.], [179., 0., -179., 1.]));
+        assert!(!bounds_intersect([-10., -90., 10., 90.], [179., 0., -179., 1.]));
+        assert!(bounds_intersect([-180., -90., 180., 90.], [0., 0., 1., 1.]));
+    }
+}
@@ -0,0 +1,530 @@
+use super::*;
+use crate::http::BoxFuture;
+
+/// `implicitTiling` of a 3D Tiles 1.1 tile.
+#[derive(Debug, serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImplicitTiling {
+    pub subdivision_scheme: SubdivisionScheme,
+    pub subtree_levels: u32,
+    #[serde(default)]
+    pub available_levels: Option<u32>,
+    /// Predecessor of `availableLevels` in the 3DTILES_implicit_tiling extension.
+    #[serde(default)]
+    pub maximum_level: Option<u32>,
+    pub subtrees: SubtreeTemplate,
+}
+
+impl ImplicitTiling {
+    pub fn levels(&self) -> u32 {
+        self.available_levels
+            .or(self.maximum_level.map(|x| x + 1))
+            .unwrap_or(self.subtree_levels)
+    }
+
+    fn branching(&self) -> u64 {
+        match self.subdivision_scheme {
+            SubdivisionScheme::Quadtree => 4,
+            SubdivisionScheme::Octree => 8,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SubdivisionScheme {
+    #[serde(rename = "QUADTREE")]
+    Quadtree,
+    #[serde(rename = "OCTREE")]
+    Octree,
+}
+
+#[derive(Debug, serde::Deserialize, Clone)]
+pub struct SubtreeTemplate {
+    pub uri: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImplicitCoord {
+    pub level: u32,
+    pub x: u64,
+    pub y: u64,
+    pub z: u64,
+}
+
+impl ImplicitCoord {
+    pub fn children(&self, scheme: SubdivisionScheme) -> Vec<Self> {
+        let zs: &[u64] = match scheme {
+            SubdivisionScheme::Quadtree => &[0],
+            SubdivisionScheme::Octree => &[0, 1],
+        };
+        let mut children = vec![];
+        for &z in zs {
+            for y in 0..2 {
+                for x in 0..2 {
+                    children.push(Self {
+                        level: self.level + 1,
+                        x: self.x * 2 + x,
+                        y: self.y * 2 + y,
+                        z: self.z * 2 + z,
+                    });
+                }
+            }
+        }
+        children
+    }
+
+    /// Position of this coordinate relative to `root`, which must be one of its ancestors.
+    fn relative_to(&self, root: &Self) -> Self {
+        let d = self.level - root.level;
+        Self {
+            level: d,
+            x: self.x - (root.x << d),
+            y: self.y - (root.y << d),
+            z: self.z - (root.z << d),
+        }
+    }
+
+    fn morton(&self, scheme: SubdivisionScheme) -> u64 {
+        let mut m = 0;
+        for i in 0..self.level {
+            match scheme {
+                SubdivisionScheme::Quadtree => {
+                    m |= (self.x >> i & 1) << (2 * i);
+                    m |= (self.y >> i & 1) << (2 * i + 1);
+                }
+                SubdivisionScheme::Octree => {
+                    m |= (self.x >> i & 1) << (3 * i);
+                    m |= (self.y >> i & 1) << (3 * i + 1);
+                    m |= (self.z >> i & 1) << (3 * i + 2);
+                }
+            }
+        }
+        m
+    }
+
+    /// Fills `{level}`, `{x}`, `{y}` and `{z}` of a template uri.
+    pub fn substitute(&self, template: &str) -> String {
+        template
+            .replace("{level}", &self.level.to_string())
+            .replace("{x}", &self.x.to_string())
+            .replace("{y}", &self.y.to_string())
+            .replace("{z}", &self.z.to_string())
+    }
+}
+
+/// Everything of the implicit root tile needed to expand its subtrees.
+#[derive(Debug)]
+pub struct ImplicitRoot {
+    pub tiling: ImplicitTiling,
+    /// Uri of the tileset the templates are relative to.
+    pub base: String,
+    pub content: Option<String>,
+    pub bounding: BoundingVolume,
+    pub geometric_error: f64,
+}
+
+impl ImplicitRoot {
+    pub fn bounding_of(&self, c: &ImplicitCoord) -> BoundingVolume {
+        let n = (1u64 << c.level) as f64;
+        let octree = self.tiling.subdivision_scheme == SubdivisionScheme::Octree;
+        if let Some([west, south, east, north, min_h, max_h]) = self.bounding.region {
+            let lon = |x: f64| west + (east - west) * x / n;
+            let lat = |y: f64| south + (north - south) * y / n;
+            let (bottom, top) = if octree {
+                let h = |z: f64| min_h + (max_h - min_h) * z / n;
+                (h(c.z as f64), h(c.z as f64 + 1.))
+            } else {
+                (min_h, max_h)
+            };
+            return BoundingVolume::from_region([
+                lon(c.x as f64),
+                lat(c.y as f64),
+                lon(c.x as f64 + 1.),
+                lat(c.y as f64 + 1.),
+                bottom,
+                top,
+            ]);
+        }
+
+        let b = &self.bounding;
+        let offset = |i: u64| (2. * i as f64 + 1.) / n - 1.;
+        let (z_offset, z_scale) = if octree { (offset(c.z), 1. / n) } else { (0., 1.) };
+        BoundingVolume {
+            center: b.center + b.x_axis * offset(c.x) + b.y_axis * offset(c.y) + b.z_axis * z_offset,
+            x_axis: b.x_axis / n,
+            y_axis: b.y_axis / n,
+            z_axis: b.z_axis * z_scale,
+            region: None,
+        }
+    }
+}
+
+/// A subtree of an implicit tileset that still has to be loaded.
+#[derive(Debug)]
+pub struct SubtreeRequest {
+    pub root: Arc<ImplicitRoot>,
+    /// Coordinate of the root tile of the subtree.
+    pub coord: ImplicitCoord,
+}
+
+enum Availability {
+    Constant(bool),
+    Bits(Vec<u8>),
+}
+
+impl Availability {
+    fn get(&self, i: u64) -> bool {
+        match self {
+            Availability::Constant(c) => *c,
+            Availability::Bits(bits) => bits
+                .get((i / 8) as usize)
+                .is_some_and(|b| b >> (i % 8) & 1 == 1),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SubtreeJson {
+    #[serde(default)]
+    buffers: Vec<BufferJson>,
+    #[serde(default)]
+    buffer_views: Vec<BufferViewJson>,
+    tile_availability: AvailabilityJson,
+    #[serde(default)]
+    content_availability: Vec<AvailabilityJson>,
+    child_subtree_availability: AvailabilityJson,
+}
+
+#[derive(serde::Deserialize)]
+struct BufferJson {
+    uri: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BufferViewJson {
+    buffer: usize,
+    #[serde(default)]
+    byte_offset: usize,
+    byte_length: usize,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AvailabilityJson {
+    #[serde(alias = "bufferView")]
+    bitstream: Option<usize>,
+    constant: Option<u8>,
+}
+
+struct Subtree {
+    tiles: Availability,
+    content: Option<Availability>,
+    child_subtrees: Availability,
+}
+
+/// Splits a binary `.subtree` into its json and binary chunk, json subtrees have no binary chunk.
+fn split_subtree(bytes: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    if !bytes.starts_with(b"subt") {
+        return Ok((bytes, &[]));
+    }
+    let header = bytes.get(..24).ok_or("subtree header too short")?;
+    let json_len = u64::from_le_bytes(header[8..16].try_into().unwrap()) as usize;
+    let bin_len = u64::from_le_bytes(header[16..24].try_into().unwrap()) as usize;
+    let json = bytes.get(24..24 + json_len).ok_or("subtree json truncated")?;
+    let bin = bytes
+        .get(24 + json_len..24 + json_len + bin_len)
+        .ok_or("subtree binary truncated")?;
+    Ok((json, bin))
+}
+
+async fn load_subtree<S: TileSource + ?Sized>(
+    uri: &str,
+    source: &S,
+) -> Result<Subtree, TileSourceError> {
+    let bytes = source.download(uri).await?;
+    let (json, bin) = split_subtree(&bytes).map_err(|x| format!("{uri}: {x}"))?;
+    let json: SubtreeJson = serde_json::from_slice(json).map_err(|x| format!("{uri}: {x}"))?;
+
+    let mut buffers = vec![];
+    for b in json.buffers.iter() {
+        match &b.uri {
+            Some(u) => buffers.push(source.download(&source.resolve_uri(uri, u)).await?),
+            None => buffers.push(bin.to_vec()),
+        }
+    }
+    let availability = |a: &AvailabilityJson| -> Result<Availability, TileSourceError> {
+        if let Some(view) = a.bitstream {
+            let bits = json
+                .buffer_views
+                .get(view)
+                .and_then(|v| buffers.get(v.buffer)?.get(v.byte_offset..v.byte_offset + v.byte_length))
+                .ok_or(format!("{uri}: invalid bitstream {view}"))?;
+            return Ok(Availability::Bits(bits.to_vec()));
+        }
+        Ok(Availability::Constant(a.constant.unwrap_or(0) == 1))
+    };
+
+    Ok(Subtree {
+        tiles: availability(&json.tile_availability)?,
+        content: match json.content_availability.first() {
+            Some(a) => Some(availability(a)?),
+            None => None,
+        },
+        child_subtrees: availability(&json.child_subtree_availability)?,
+    })
+}
+
+impl SubtreeRequest {
+    pub fn uri(&self) -> String {
+        self.coord.substitute(&self.root.tiling.subtrees.uri)
+    }
+
+    /// Loads the subtree and returns its tiles as explicit nodes with uris relative to the
+    /// tileset. Child subtrees become placeholder nodes that are loaded once they are needed.
+    pub async fn load<S: TileSource + ?Sized>(
+        &self,
+        uri: &str,
+        source: &S,
+    ) -> Result<Node, TileSourceError> {
+        let subtree = load_subtree(uri, source).await?;
+        Ok(self.build(&subtree, self.coord).unwrap_or_default())
+    }
+
+    fn build(&self, subtree: &Subtree, coord: ImplicitCoord) -> Option<Node> {
+        let tiling = &self.root.tiling;
+        let scheme = tiling.subdivision_scheme;
+        let local = coord.relative_to(&self.coord);
+        let n = tiling.branching();
+        let index = (n.pow(local.level) - 1) / (n - 1) + local.morton(scheme);
+        if !subtree.tiles.get(index) {
+            return None;
+        }
+
+        let mut node = Node {
+            bounding: self.root.bounding_of(&coord),
+            err: self.root.geometric_error / (1u64 << coord.level) as f64,
+            ..Default::default()
+        };
+        if let (Some(template), Some(content)) = (&self.root.content, &subtree.content) {
+            if content.get(index) {
+                node.content = Some(Content {
+                    uri: coord.substitute(template),
+                });
+            }
+        }
+
+        if coord.level + 1 >= tiling.levels() {
+            return Some(node);
+        }
+        for child in coord.children(scheme) {
+            if local.level + 1 < tiling.subtree_levels {
+                node.children.extend(self.build(subtree, child));
+            } else if subtree.child_subtrees.get(child.relative_to(&self.coord).morton(scheme)) {
+                let request = SubtreeRequest {
+                    root: self.root.clone(),
+                    coord: child,
+                };
+                node.children.push(Node {
+                    bounding: self.root.bounding_of(&child),
+                    err: self.root.geometric_error / (1u64 << child.level) as f64,
+                    content: Some(Content { uri: request.uri() }),
+                    subtree: Some(Arc::new(request)),
+                    ..Default::default()
+                });
+            }
+        }
+        Some(node)
+    }
+}
+
+/// Replaces every implicit root below `node` by the tiles of its root subtree.
+pub fn expand_implicit<'a, S: TileSource + ?Sized>(
+    node: &'a mut Node,
+    base: &'a str,
+    source: &'a S,
+) -> BoxFuture<'a, Result<(), TileSourceError>> {
+    Box::pin(async move {
+        if let Some(tiling) = node.implicit_tiling.take() {
+            let request = SubtreeRequest {
+                root: Arc::new(ImplicitRoot {
+                    tiling,
+                    base: base.to_string(),
+                    content: node.content.take().map(|c| c.uri),
+                    bounding: node.bounding.clone(),
+                    geometric_error: node.err,
+                }),
+                coord: ImplicitCoord::default(),
+            };
+            let uri = source.resolve_uri(base, &request.uri());
+            let mut expanded = request.load(&uri, source).await?;
+            expanded.transform = node.transform;
+            expanded.refine = node.refine;
+            *node = expanded;
+            return Ok(());
+        }
+        for child in node.children.iter_mut() {
+            expand_implicit(child, base, source).await?;
+        }
+        Ok(())
+    })
+}
+
+/// Loads a subtree below the tile `parent` once the traversal reaches it.
+pub fn get_subtree(
+    uri: String,
+    parent: String,
+    request: Arc<SubtreeRequest>,
+    c: &Arc<dyn TileSource>,
+) -> NodeRequest {
+    let c = c.clone();
+    let cancel = crate::http::CancelToken::default();
+    let (sender, promise) = poll_promise::Promise::new();
+    let path = uri.clone();
+    let token = cancel.clone();
+    crate::http::execute(async move {
+        if token.is_cancelled() {
+            sender.send(Err(TileSourceError::Other("cancelled".into())));
+            token.finish();
+            return;
+        }
+        let node = request.load(&path, c.as_ref()).await.map(|mut node| {
+            node.resolve_uris(&request.root.base, c.as_ref());
+            node
+        });
+        sender.send(node);
+        token.finish();
+    });
+    return NodeRequest {
+        parent,
+        uri,
+        promise,
+        cancel,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coord(level: u32, x: u64, y: u64, z: u64) -> ImplicitCoord {
+        ImplicitCoord { level, x, y, z }
+    }
+
+    fn request(scheme: SubdivisionScheme, subtree_levels: u32, levels: u32) -> SubtreeRequest {
+        SubtreeRequest {
+            root: Arc::new(ImplicitRoot {
+                tiling: ImplicitTiling {
+                    subdivision_scheme: scheme,
+                    subtree_levels,
+                    available_levels: Some(levels),
+                    maximum_level: None,
+                    subtrees: SubtreeTemplate {
+                        uri: "subtrees/{level}/{x}/{y}.subtree".into(),
+                    },
+                },
+                base: "tileset.json".into(),
+                content: Some("tiles/{level}/{x}/{y}.glb".into()),
+                bounding: BoundingVolume {
+                    center: glam::DVec3::ZERO,
+                    x_axis: glam::dvec3(8., 0., 0.),
+                    y_axis: glam::dvec3(0., 8., 0.),
+                    z_axis: glam::dvec3(0., 0., 8.),
+                    region: None,
+                },
+                geometric_error: 64.,
+            }),
+            coord: ImplicitCoord::default(),
+        }
+    }
+
+    #[test]
+    fn morton_interleaves_the_bits() {
+        let quad = SubdivisionScheme::Quadtree;
+        assert_eq!(coord(1, 1, 0, 0).morton(quad), 0b01);
+        assert_eq!(coord(1, 0, 1, 0).morton(quad), 0b10);
+        assert_eq!(coord(2, 3, 1, 0).morton(quad), 0b0111);
+        assert_eq!(coord(2, 2, 3, 0).morton(quad), 0b1110);
+        let oct = SubdivisionScheme::Octree;
+        assert_eq!(coord(1, 0, 0, 1).morton(oct), 0b100);
+        assert_eq!(coord(2, 1, 2, 3).morton(oct), 0b110_101);
+    }
+
+    #[test]
+    fn children_follow_morton_order() {
+        for scheme in [SubdivisionScheme::Quadtree, SubdivisionScheme::Octree] {
+            let parent = coord(1, 1, 0, 0);
+            let children = parent.children(scheme);
+            for (i, child) in children.iter().enumerate() {
+                assert_eq!(child.level, 2);
+                assert_eq!(child.relative_to(&parent).morton(scheme), i as u64);
+            }
+        }
+        assert_eq!(coord(3, 5, 6, 0).relative_to(&coord(1, 1, 1, 0)), coord(2, 1, 2, 0));
+    }
+
+    #[test]
+    fn split_binary_subtree() {
+        let json = br#"{"a":1}"#;
+        let mut bytes = b"subt".to_vec();
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend((json.len() as u64).to_le_bytes());
+        bytes.extend(3u64.to_le_bytes());
+        bytes.extend(json);
+        bytes.extend([1, 2, 3]);
+        assert_eq!(split_subtree(&bytes), Ok((&json[..], &[1u8, 2, 3][..])));
+        assert!(split_subtree(&bytes[..bytes.len() - 1]).is_err());
+        assert_eq!(split_subtree(json), Ok((&json[..], &b""[..])));
+    }
+
+    #[test]
+    fn bounding_of_child() {
+        let request = request(SubdivisionScheme::Quadtree, 2, 2);
+        let b = request.root.bounding_of(&coord(1, 1, 0, 0));
+        assert_eq!(b.center, glam::dvec3(4., -4., 0.));
+        assert_eq!(b.x_axis, glam::dvec3(4., 0., 0.));
+        assert_eq!(b.y_axis, glam::dvec3(0., 4., 0.));
+        assert_eq!(b.z_axis, glam::dvec3(0., 0., 8.));
+    }
+
+    #[test]
+    fn build_only_available_tiles() {
+        let request = request(SubdivisionScheme::Quadtree, 2, 2);
+        // the root and its child with morton index 1
+        let subtree = Subtree {
+            tiles: Availability::Bits(vec![0b0000_0101]),
+            content: Some(Availability::Constant(true)),
+            child_subtrees: Availability::Constant(false),
+        };
+        let node = request.build(&subtree, request.coord).unwrap();
+        assert_eq!(node.content.unwrap().uri, "tiles/0/0/0.glb");
+        assert_eq!(node.err, 64.);
+        assert_eq!(node.children.len(), 1);
+        let child = &node.children[0];
+        assert_eq!(child.content.as_ref().unwrap().uri, "tiles/1/1/0.glb");
+        assert_eq!(child.err, 32.);
+        assert!(child.children.is_empty());
+    }
+
+    #[test]
+    fn build_child_subtree_placeholders() {
+        let request = request(SubdivisionScheme::Quadtree, 2, 3);
+        // only the first child subtree below the first level 1 tile is available
+        let subtree = Subtree {
+            tiles: Availability::Constant(true),
+            content: None,
+            child_subtrees: Availability::Bits(vec![0b0000_0001, 0]),
+        };
+        let node = request.build(&subtree, request.coord).unwrap();
+        assert!(node.content.is_none());
+        assert_eq!(node.children.len(), 4);
+        assert_eq!(node.children[0].children.len(), 1);
+        assert!(node.children[1..].iter().all(|c| c.children.is_empty()));
+
+        let placeholder = &node.children[0].children[0];
+        assert_eq!(placeholder.content.as_ref().unwrap().uri, "subtrees/2/0/0.subtree");
+        assert_eq!(placeholder.err, 16.);
+        assert_eq!(placeholder.subtree.as_ref().unwrap().coord, coord(2, 0, 0, 0));
+    }
+}
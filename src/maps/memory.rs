@@ -0,0 +1,157 @@
+use super::*;
+
+fn mesh_bytes(mesh: &three_d::CpuMesh) -> usize {
+    let positions = match &mesh.positions {
+        three_d::Positions::F32(p) => p.len() * 12,
+        three_d::Positions::F64(p) => p.len() * 24,
+    };
+    let indices = match &mesh.indices {
+        three_d::Indices::U8(i) => i.len(),
+        three_d::Indices::U16(i) => i.len() * 2,
+        three_d::Indices::U32(i) => i.len() * 4,
+        three_d::Indices::None => 0,
+    };
+    let uvs = mesh.uvs.as_ref().map(|x| x.len() * 8).unwrap_or_default();
+    let normals = mesh.normals.as_ref().map(|x| x.len() * 12).unwrap_or_default();
+    positions + indices + uvs + normals
+}
+
+fn texture_bytes(texture: &three_d::CpuTexture) -> usize {
+    let pixel = match &texture.data {
+        three_d::TextureData::RU8(_) => 1,
+        three_d::TextureData::RgU8(_) => 2,
+        three_d::TextureData::RgbU8(_) => 3,
+        three_d::TextureData::RgbF32(_) => 12,
+        three_d::TextureData::RgbaF32(_) => 16,
+        _ => 4,
+    };
+    (texture.width * texture.height) as usize * pixel
+}
+
+/// Bytes of `texture` once uploaded, mipmaps add a third.
+pub(crate) fn texture_gpu_bytes(texture: &three_d::CpuTexture) -> usize {
+    match texture.mipmap {
+        Some(_) => texture_bytes(texture) * 4 / 3,
+        None => texture_bytes(texture),
+    }
+}
+
+fn image_bytes(image: &TileImage) -> usize {
+    match image {
+        TileImage::Pixels(texture) => texture_bytes(texture),
+        TileImage::Compressed(texture) => texture.byte_size(),
+    }
+}
+
+fn image_gpu_bytes(image: &TileImage) -> usize {
+    match image {
+        TileImage::Pixels(texture) => texture_gpu_bytes(texture),
+        TileImage::Compressed(texture) => texture.byte_size(),
+    }
+}
+
+impl TileContent {
+    /// Bytes this content occupies while decoded on the CPU.
+    pub fn byte_size(&self) -> usize {
+        mesh_bytes(&self.mesh) + self.texture.as_ref().map(image_bytes).unwrap_or_default()
+    }
+
+    /// Bytes once uploaded.
+    pub fn gpu_byte_size(&self) -> usize {
+        mesh_bytes(&self.mesh) + self.texture.as_ref().map(image_gpu_bytes).unwrap_or_default()
+    }
+}
+
+impl Tile {
+    /// Returns the `(cpu, gpu)` bytes held by the content of this tile.
+    pub fn memory_usage(&self) -> (usize, usize) {
+        match &self.content {
+            TileContentState::Loading { promise, .. } => match promise.ready() {
+                Some(Ok(r)) => (r.iter().map(|c| c.byte_size()).sum(), 0),
+                _ => (0, 0),
+            },
+            TileContentState::Decoded(contents) => (contents.iter().map(|c| c.byte_size()).sum(), 0),
+            TileContentState::Ready(contents) => (
+                contents
+                    .iter()
+                    .filter_map(|c| c.pick_mesh.as_ref())
+                    .map(|m| m.byte_size())
+                    .sum(),
+                contents.iter().map(|c| c.gpu_bytes).sum(),
+            ),
+            TileContentState::None | TileContentState::Failed { .. } => (0, 0),
+        }
+    }
+}
+
+/// Content released by [`TileCache::evict`].
+enum Eviction {
+    Tile(String),
+    /// Raster overlay tile of the layer with this [`DrapeLayer::id`].
+    Overlay(u64, [u32; 3]),
+}
+
+impl TileCache {
+    /// Returns the `(cpu, gpu)` bytes held by all loaded tile contents and overlay tiles.
+    pub fn memory_usage(&self) -> (usize, usize) {
+        let mut cpu = 0;
+        let mut gpu = 0;
+        for t in self.cache.values() {
+            let (c, g) = t.memory_usage();
+            cpu += c;
+            gpu += g;
+        }
+        (cpu, gpu + self.overlay_memory_usage())
+    }
+
+    /// Releases the content of the least recently selected tiles until both budgets are met.
+    /// Tiles traversed within the last `eviction_min_age` frames are kept, as are the
+    /// selected tiles and all their ancestors, which `select_tiles` may still fall back to.
+    pub fn evict(&mut self) {
+        let (mut cpu, mut gpu) = self.memory_usage();
+        if cpu <= self.settings.cpu_budget && gpu <= self.settings.gpu_budget {
+            return;
+        }
+
+        let mut protected = std::collections::HashSet::new();
+        for id in self.selection.selected.iter() {
+            let mut id = Some(id);
+            while let Some(current) = id {
+                if !protected.insert(current) {
+                    break;
+                }
+                id = self.cache.get(current).and_then(|t| t.parent.as_ref());
+            }
+        }
+        let mut candidates: Vec<_> = self
+            .cache
+            .iter()
+            .filter(|(id, _)| !protected.contains(id))
+            .filter(|(_, t)| self.frame.saturating_sub(t.last_used) > self.settings.eviction_min_age)
+            .filter(|(_, t)| t.memory_usage() != (0, 0))
+            .map(|(id, t)| (t.last_used, Eviction::Tile(id.clone())))
+            .collect();
+        for (last_used, layer, tile) in self.overlay_eviction_candidates() {
+            candidates.push((last_used, Eviction::Overlay(layer, tile)));
+        }
+        candidates.sort_by_key(|c| c.0);
+
+        for (_, candidate) in candidates {
+            if cpu <= self.settings.cpu_budget && gpu <= self.settings.gpu_budget {
+                break;
+            }
+            match candidate {
+                Eviction::Tile(id) => {
+                    if let Some(t) = self.cache.get_mut(&id) {
+                        let (c, g) = t.memory_usage();
+                        cpu -= c;
+                        gpu -= g;
+                        t.content = TileContentState::None;
+                        emit(&mut self.subscribers, TileEvent::Evicted { id });
+                    }
+                }
+                Eviction::Overlay(layer, tile) => gpu -= self.evict_overlay(layer, tile),
+            }
+        }
+    }
+}
@@ -1,6 +1,5 @@
 use glam::{DMat4, DVec3, Vec4Swizzles};
 use std::sync::Arc;
-use three_d::Geometry;
 
 mod tiles;
 pub use tiles::*;
@@ -14,35 +13,236 @@ pub use view_state::*;
 mod obb;
 pub use obb::*;
 
+mod source;
+pub use source::*;
+
+mod disk_cache;
+pub use disk_cache::*;
+
+mod memory;
+pub use memory::*;
+
+mod scheduler;
+pub use scheduler::*;
+
+mod implicit;
+pub use implicit::*;
+
+mod draco;
+pub use draco::*;
+
+mod texture;
+pub use texture::*;
+
+mod selection;
+pub use selection::*;
+
+mod stats;
+pub use stats::*;
+
+mod attribution;
+pub use attribution::*;
+
+mod quantized_mesh;
+pub use quantized_mesh::*;
+
+mod raster;
+pub use raster::*;
+
+mod drape;
+pub use drape::*;
+
+mod picking;
+pub use picking::*;
+
 
 pub struct TileContent {
     mesh: three_d::CpuMesh,
-    texture: three_d::CpuTexture,
-    mat: glam::Mat4,
+    /// Base colour texture of the material, if any.
+    texture: Option<TileImage>,
+    /// Base colour factor of the material.
+    color: three_d::Srgba,
+    double_sided: bool,
+    mat: glam::DMat4,
+    /// `asset.copyright` of the glb the content was loaded from.
+    copyright: Option<String>,
 }
 
 pub struct TileContentGPU {
     mesh_gpu: three_d::Mesh,
-    texture_gpu: three_d::Texture2DRef,
+    texture_gpu: Option<TileTextureGPU>,
+    color: three_d::Srgba,
+    double_sided: bool,
+    gpu_bytes: usize,
+    /// CPU copy of the triangles, see [`TileCacheSettings::pick_meshes`].
+    pick_mesh: Option<PickMesh>,
 }
 
 pub enum TileContentState {
     None,
-    Loading(poll_promise::Promise<Vec<TileContent>>),
+    Loading {
+        promise: poll_promise::Promise<Result<Vec<TileContent>, String>>,
+        cancel: crate::http::CancelToken,
+        /// Failed attempts before this one.
+        attempts: u32,
+    },
+    /// Decoded and waiting in the upload queue of [`TileCache::upload`].
+    Decoded(Vec<TileContent>),
     Ready(Vec<TileContentGPU>),
+    /// Loading failed, it is requested again once `retry_at` ([`crate::http::now`]) has passed.
+    Failed {
+        error: String,
+        attempts: u32,
+        retry_at: f64,
+    },
+}
+
+/// External tileset json requested for the tile `parent`.
+pub struct NodeRequest {
+    pub parent: String,
+    pub uri: String,
+    pub promise: poll_promise::Promise<Result<Node, TileSourceError>>,
+    pub cancel: crate::http::CancelToken,
+}
+
+#[derive(Clone, Debug)]
+pub struct TileFailure {
+    pub uri: String,
+    pub error: String,
+    pub attempts: u32,
+    pub retry_at: f64,
+}
+
+pub struct TileCacheSettings {
+    /// Maximum bytes of decoded tile content waiting on the CPU.
+    pub cpu_budget: usize,
+    /// Maximum bytes of meshes and textures uploaded to the GPU.
+    pub gpu_budget: usize,
+    /// Number of frames a tile must not have been traversed before its content may be evicted.
+    pub eviction_min_age: u64,
+    /// Maximum number of node and content requests in flight at once. Natively the requests
+    /// run on as many threads, see [`crate::http::reserve_workers`].
+    pub max_requests: usize,
+    /// Requests of tiles that have not been traversed for this many frames are cancelled.
+    /// Downloads that already started still finish, only their decoding is skipped.
+    pub cancel_after_frames: u64,
+    /// Delay in seconds before the first retry of a failed request, doubled on every further failure.
+    pub retry_delay: f64,
+    pub max_retry_delay: f64,
+    /// Failed requests are given up after this many attempts.
+    pub max_attempts: u32,
+    /// Tiles with a larger screen space error in pixels are refined.
+    pub maximum_screen_space_error: f64,
+    /// Maximum depth of the traversal below the roots.
+    pub max_level: usize,
+    /// Raises the screen space error target while frames are slow or requests pile up.
+    pub adaptive: bool,
+    /// Upper limit of the adaptive screen space error target.
+    pub max_adaptive_screen_space_error: f64,
+    /// Frame time in seconds above which the adaptive target is raised.
+    pub frame_time_budget: f64,
+    /// Number of in flight and waiting requests above which the adaptive target is raised.
+    pub pending_requests_budget: usize,
+    /// Filtering of tile textures, applies to tiles uploaded after a change.
+    pub texture_quality: TextureQuality,
+    /// Has to match the [`crate::threed_view::View`] the tiles are rendered into.
+    pub depth_mode: crate::threed_view::DepthMode,
+    /// Maximum bytes of meshes and textures uploaded to the GPU per frame.
+    pub upload_budget_bytes: usize,
+    /// Maximum time in seconds spent uploading per frame.
+    pub upload_budget_time: f64,
+    /// Keeps a CPU copy of the triangles of uploaded tiles for [`TileCache::pick_ray`],
+    /// applies to tiles uploaded after a change. Off by default, the copy costs about as
+    /// much memory as the meshes themselves.
+    pub pick_meshes: bool,
+}
+
+impl TileCacheSettings {
+    /// Time of the next attempt after the `attempts`th failure.
+    pub fn retry_at(&self, attempts: u32) -> f64 {
+        if attempts >= self.max_attempts {
+            return f64::INFINITY;
+        }
+        let delay = self.retry_delay * 2f64.powi(attempts.saturating_sub(1) as i32);
+        crate::http::now() + delay.min(self.max_retry_delay)
+    }
+}
+
+impl Default for TileCacheSettings {
+    fn default() -> Self {
+        Self {
+            cpu_budget: 256 * 1024 * 1024,
+            gpu_budget: 1024 * 1024 * 1024,
+            eviction_min_age: 60,
+            max_requests: 12,
+            cancel_after_frames: 30,
+            retry_delay: 1.,
+            max_retry_delay: 60.,
+            max_attempts: 6,
+            maximum_screen_space_error: 16.,
+            max_level: 20,
+            adaptive: false,
+            max_adaptive_screen_space_error: 64.,
+            frame_time_budget: 1. / 30.,
+            pending_requests_budget: 64,
+            texture_quality: TextureQuality::default(),
+            depth_mode: Default::default(),
+            upload_budget_bytes: 16_000_000,
+            upload_budget_time: 0.004,
+            pick_meshes: false,
+        }
+    }
 }
 
 pub struct TileCache {
-    pub client: poll_promise::Promise<Arc<RestClient>>,
+    pub settings: TileCacheSettings,
+    /// Incremented on every [`TileCache::render`].
+    pub frame: u64,
+    pub source: Arc<dyn TileSource>,
+    pub root: poll_promise::Promise<Result<Node, TileSourceError>>,
     pub cache: std::collections::HashMap<String, Tile>,
     pub roots: Vec<String>,
-    pub node_promises: Vec<poll_promise::Promise<(String, Node)>>,
+    pub node_promises: Vec<NodeRequest>,
+    pub scheduler: RequestScheduler,
     pub material: three_d::ColorMaterial,
     pub has_load_root: bool,
+    /// Screen space error target of the last frame, differs from the setting in adaptive mode.
+    pub screen_space_error: f64,
+    /// Smoothed time between the last calls of [`TileCache::render`] in seconds.
+    pub frame_time: f64,
+    pub last_render: f64,
+    /// Selection of the last [`TileCache::render`].
+    pub selection: TileSelection,
+    pub counters: Arc<TileLoadCounters>,
+    pub subscribers: Vec<std::sync::mpsc::Sender<TileEvent>>,
+    /// Raster layers draped over the tiles, later ones on top.
+    pub overlays: Vec<DrapeLayer>,
+    /// Format basis textures are transcoded to for the context of this cache.
+    pub compressed_target: Option<CompressedFormat>,
 }
 
 impl TileCache {
+    /// Streams Google Photorealistic 3D Tiles using the given api key.
     pub fn new(ctx3d: &three_d::Context, key: String) -> Self {
+        Self::with_source(ctx3d, Arc::new(RestClient::new(key)))
+    }
+
+    /// Streams a standard 3D Tiles `tileset.json` from a url or a local directory.
+    pub fn new_tileset(ctx3d: &three_d::Context, url: impl Into<String>) -> Self {
+        Self::with_source(ctx3d, Arc::new(TilesetSource::new(url)))
+    }
+
+    /// Streams Cesium quantized-mesh terrain from a url or a local directory with a `layer.json`.
+    pub fn new_terrain(ctx3d: &three_d::Context, url: impl Into<String>) -> Self {
+        Self::with_source(ctx3d, Arc::new(QuantizedMeshSource::new(url)))
+    }
+
+    /// Base globe textured with raster map tiles.
+    pub fn new_raster(ctx3d: &three_d::Context, layer: RasterLayer) -> Self {
+        Self::with_source(ctx3d, Arc::new(RasterSource::new(layer)))
+    }
+
+    pub fn with_source(ctx3d: &three_d::Context, source: Arc<dyn TileSource>) -> Self {
         let mut m = three_d::ColorMaterial::new(
             ctx3d,
             &three_d::CpuMaterial {
@@ -52,242 +252,508 @@ impl TileCache {
         );
         m.render_states.cull = three_d::Cull::Back;
 
-        let (sender, client) = poll_promise::Promise::new();
+        let (sender, root) = poll_promise::Promise::new();
+        let s = source.clone();
         crate::http::execute(async move {
-            let client = Arc::new(RestClient::new(key).await.unwrap_or_default());
-            sender.send(client);
+            sender.send(s.get_root().await);
         });
 
         let cache = Default::default();
 
+        let settings = TileCacheSettings::default();
         let s = Self {
-            client,
+            screen_space_error: settings.maximum_screen_space_error,
+            frame_time: 0.,
+            last_render: 0.,
+            settings,
+            frame: 0,
+            source,
+            root,
             cache,
             material: m,
             roots: Default::default(),
             node_promises: Default::default(),
+            scheduler: Default::default(),
             has_load_root: false,
+            selection: Default::default(),
+            counters: Default::default(),
+            subscribers: vec![],
+            overlays: vec![],
+            compressed_target: compressed_target(ctx3d),
         };
 
         return s;
     }
 
     pub fn load(&mut self, ctx3d: &three_d::Context) {
-        if let Some(client) = self.client.ready() {
-            if client.session == "" {
-                return;
-            }
+        if let Some(Ok(root)) = self.root.ready() {
             if !self.has_load_root {
                 self.has_load_root = true;
+                self.scheduler.register_subtrees(root);
                 Tile::fill(
-                    &client.root,
-                    &client,
+                    root,
+                    &self.source,
                     None,
+                    Refine::Replace,
+                    DMat4::IDENTITY,
                     &mut self.cache,
                     &mut self.roots,
                     true,
-                    ctx3d,
+                    Some(ctx3d),
                 );
             }
             let mut items_to_remove = vec![];
             for (i, a) in self.node_promises.iter_mut().enumerate() {
-                if let Some((parent, node)) = a.ready_mut() {
+                let parent = &a.parent;
+                if let Some(node) = a.promise.ready_mut() {
                     items_to_remove.push(i);
+                    let node = match node {
+                        Ok(node) => node,
+                        Err(error) => {
+                            let attempts = self
+                                .scheduler
+                                .node_failures
+                                .get(&a.uri)
+                                .map(|f| f.attempts)
+                                .unwrap_or_default()
+                                + 1;
+                            self.scheduler.node_failures.insert(
+                                a.uri.clone(),
+                                TileFailure {
+                                    uri: a.uri.clone(),
+                                    error: error.to_string(),
+                                    attempts,
+                                    retry_at: self.settings.retry_at(attempts),
+                                },
+                            );
+                            emit(
+                                &mut self.subscribers,
+                                TileEvent::NodeFailed {
+                                    uri: a.uri.clone(),
+                                    error: error.to_string(),
+                                },
+                            );
+                            if let Some(p) = self.cache.get_mut(parent) {
+                                p.child_options.push(a.uri.clone());
+                            }
+                            continue;
+                        }
+                    };
+                    self.scheduler.node_failures.remove(&a.uri);
+                    emit(
+                        &mut self.subscribers,
+                        TileEvent::NodeLoaded {
+                            parent: parent.clone(),
+                            uri: a.uri.clone(),
+                        },
+                    );
+                    let (refine, transform) = self
+                        .cache
+                        .get(parent)
+                        .map(|p| (p.refine, p.transform))
+                        .unwrap_or((Refine::Replace, DMat4::IDENTITY));
+                    self.scheduler.register_subtrees(node);
                     let mut roots = vec![];
-                    Tile::fill(
+                    let uris = Tile::fill(
                         &node,
-                        &client,
+                        &self.source,
                         Some(&parent),
+                        refine,
+                        transform,
                         &mut self.cache,
                         &mut roots,
                         true,
-                        ctx3d,
+                        Some(ctx3d),
                     );
                     if let Some(p) = self.cache.get_mut(parent) {
                         p.children.append(&mut roots);
+                        p.child_options
+                            .extend(uris.into_iter().filter(|u| !self.source.is_content(u)));
                     }
                 }
             }
             for i in items_to_remove.into_iter().rev() {
                 let _ = self.node_promises.remove(i);
             }
-            for (_, t) in self.cache.iter_mut() {
-                if let TileContentState::Loading(l) = &mut t.content {
-                    if let Some(r) = l.ready_mut() {
-                        let mut contents = vec![];
-
-                        for r in r.iter() {
-                            let mut mesh_gpu = three_d::Mesh::new(&ctx3d, &r.mesh);
-                            mesh_gpu.set_transformation(glam_to_three_d(&r.mat));
-
-                            let texture_gpu =
-                                three_d::Texture2DRef::from_cpu_texture(&ctx3d, &r.texture);
-
-                            contents.push(TileContentGPU {
-                                mesh_gpu,
-                                texture_gpu,
-                            });
-                        }
-                        t.content = TileContentState::Ready(contents);
+            for (id, t) in self.cache.iter_mut() {
+                let mut next = None;
+                if let TileContentState::Loading {
+                    promise, attempts, ..
+                } = &mut t.content
+                {
+                    if let Some(Err(error)) = promise.ready() {
+                        emit(
+                            &mut self.subscribers,
+                            TileEvent::Failed {
+                                id: id.clone(),
+                                error: error.clone(),
+                                attempts: *attempts + 1,
+                            },
+                        );
+                        next = Some(TileContentState::Failed {
+                            error: error.clone(),
+                            attempts: *attempts + 1,
+                            retry_at: self.settings.retry_at(*attempts + 1),
+                        });
+                    }
+                    if let Some(Ok(r)) = promise.ready_mut() {
+                        next = Some(TileContentState::Decoded(std::mem::take(r)));
                     }
                 }
+                if let Some(next) = next {
+                    t.content = next;
+                }
             }
         }
+        self.upload(ctx3d);
+        for overlay in self.overlays.iter_mut() {
+            overlay.load(ctx3d, &self.settings);
+        }
     }
 
-    pub fn render(
-        &mut self,
-        camera: &three_d::Camera,
-        lights: &[&dyn three_d::Light],
-        show_bounding_boxes: bool,
-    ) -> usize {
-        if let Some(client) = self.client.ready() {
-            let s = get_view_state(camera);
-            let mut counter = 0;
-            for r in self.roots.iter() {
-                render_tile(
-                    r,
-                    &mut self.cache,
-                    &s,
-                    &self.material,
-                    camera,
-                    lights,
-                    &mut counter,
-                    &client,
-                    &mut self.node_promises,
-                    20,
-                    show_bounding_boxes,
-                );
+    /// Uploads decoded tiles until the per-frame byte or time budget is spent, tiles of the
+    /// last traversal with the highest load priority first. At least one tile is uploaded
+    /// per frame, so a tile larger than the budget is not stuck.
+    pub fn upload(&mut self, ctx3d: &three_d::Context) {
+        let mut queue: Vec<_> = self
+            .cache
+            .iter()
+            .filter(|(_, t)| matches!(t.content, TileContentState::Decoded(_)))
+            .map(|(id, t)| (t.last_used, t.priority, id.clone()))
+            .collect();
+        queue.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.total_cmp(&a.1)));
+
+        let start = crate::http::now();
+        let mut bytes = 0;
+        for (_, _, id) in queue {
+            if bytes > 0
+                && (bytes >= self.settings.upload_budget_bytes
+                    || crate::http::now() - start >= self.settings.upload_budget_time)
+            {
+                break;
+            }
+            let Some(t) = self.cache.get_mut(&id) else {
+                continue;
+            };
+            let TileContentState::Decoded(contents) =
+                std::mem::replace(&mut t.content, TileContentState::None)
+            else {
+                continue;
+            };
+            // each glb of a tile may credit other providers
+            t.copyright = vec![];
+            for part in contents
+                .iter()
+                .filter_map(|c| c.copyright.as_deref())
+                .flat_map(split_copyright)
+            {
+                if !t.copyright.contains(&part) {
+                    t.copyright.push(part);
+                }
             }
-            return counter;
+            let gpu = t.upload(
+                contents,
+                self.settings.texture_quality,
+                self.settings.pick_meshes,
+                ctx3d,
+            );
+            let gpu_bytes = gpu.iter().map(|c| c.gpu_bytes).sum::<usize>();
+            bytes += gpu_bytes.max(1);
+            t.content = TileContentState::Ready(gpu);
+            emit(&mut self.subscribers, TileEvent::Loaded { id, gpu_bytes });
         }
-        return 0;
     }
-}
-
-pub fn render_tile(
-    id: &String,
-    cache: &mut std::collections::HashMap<String, Tile>,
-    s: &ViewState,
-    material: &three_d::ColorMaterial,
-    camera: &three_d::Camera,
-    lights: &[&dyn three_d::Light],
-    counter: &mut usize,
-    rest_client: &Arc<tiles::RestClient>,
-    node_promises: &mut Vec<poll_promise::Promise<(String, Node)>>,
-    max_level: usize,
-    show_bounding_boxes: bool,
-) -> (bool, bool) {
-    let mut childern = vec![];
-    let mut meet_sse = false;
-    let mut has_rendered = false;
-    let mut is_visible = false;
-
-    if let Some(t) = cache.get_mut(id) {
-        is_visible = t.bv.is_visible(s.position) && t.bv.intersects_frustum(&s.frustum);
-
-        if is_visible {
-            let meet_sse = s.does_tile_meet_sse(t);
-
-            // && t.children.iter().all(|c| cache.get(c).is_some_and(||))
-            if !t.children.is_empty() && !meet_sse && max_level > 0 {
-                childern = t.children.clone();
-            } else {
-                // load content
-                if let TileContentState::None = &t.content {
-                    t.content = TileContentState::Loading(get_contents(id.clone(), rest_client));
-                }
 
-                // load children
-                if !meet_sse && !t.child_options.is_empty() && max_level > 0 {
-                    for c in t.child_options.iter() {
-                        node_promises.push(get_node(c.clone(), id.clone(), rest_client));
-                    }
-                    t.child_options.clear();
-                }
+    /// Error of loading the tileset root, e.g. [`TileSourceError::InvalidKey`].
+    pub fn root_error(&self) -> Option<&TileSourceError> {
+        match self.root.ready() {
+            Some(Err(e)) => Some(e),
+            _ => None,
+        }
+    }
 
-                // render
-                if let TileContentState::Ready(contents) = &t.content {
-                    let mut m = material.clone();
-                    for c in contents {
-                        m.texture = Some(c.texture_gpu.clone());
-                        three_d::Geometry::render_with_material(&c.mesh_gpu, &m, camera, lights);
-                    }
-                    *counter += 1;
-                    has_rendered = true;
-                }
+    /// All tiles and external tilesets that currently failed to load.
+    pub fn failures(&self) -> Vec<TileFailure> {
+        let mut failures: Vec<_> = self.scheduler.node_failures.values().cloned().collect();
+        for (id, t) in self.cache.iter() {
+            if let TileContentState::Failed {
+                error,
+                attempts,
+                retry_at,
+            } = &t.content
+            {
+                failures.push(TileFailure {
+                    uri: id.clone(),
+                    error: error.clone(),
+                    attempts: *attempts,
+                    retry_at: *retry_at,
+                });
+            }
+        }
+        failures
+    }
 
-                // show bounding box
-                if show_bounding_boxes {
-                    let mut m = material.clone();
-                    m.texture = None;
-                    if is_visible {
-                        m.color = three_d::Srgba::WHITE;
-                    } else {
-                        m.color = three_d::Srgba::RED;
-                    }
-                    if is_visible {
-                        t.edges.render_with_material(&m, camera, lights);
-                    }
+    /// Cancels the requests of tiles that have not been traversed for
+    /// `cancel_after_frames` frames and puts them back to be requested again.
+    pub fn cancel_stale(&mut self) {
+        let frame = self.frame;
+        let max_age = self.settings.cancel_after_frames;
+        for t in self.cache.values_mut() {
+            if frame.saturating_sub(t.last_used) <= max_age {
+                continue;
+            }
+            if let TileContentState::Loading {
+                promise, cancel, ..
+            } = &t.content
+            {
+                if promise.ready().is_none() {
+                    cancel.cancel();
+                    self.scheduler.cancelled.push(cancel.clone());
+                    t.content = TileContentState::None;
                 }
             }
         }
-    }
 
-    if !childern.is_empty() {
-        has_rendered = true;
-        for id in childern.iter() {
-            let (child_visible, child_rendered) = render_tile(
-                id,
-                cache,
-                s,
-                material,
-                camera,
-                lights,
-                counter,
-                rest_client,
-                node_promises,
-                max_level - 1,
-                show_bounding_boxes,
-            );
-            if child_visible && !child_rendered {
-                has_rendered = false;
+        let cache = &mut self.cache;
+        let cancelled = &mut self.scheduler.cancelled;
+        self.node_promises.retain(|r| {
+            if r.promise.ready().is_some() {
+                return true;
             }
+            let Some(parent) = cache.get_mut(&r.parent) else {
+                r.cancel.cancel();
+                cancelled.push(r.cancel.clone());
+                return false;
+            };
+            if frame.saturating_sub(parent.last_used) <= max_age {
+                return true;
+            }
+            r.cancel.cancel();
+            cancelled.push(r.cancel.clone());
+            parent.child_options.push(r.uri.clone());
+            false
+        });
+        self.cancel_stale_overlays();
+    }
+
+    /// Updates the screen space error target from the frame time and the pending requests.
+    /// Without adaptive mode the target is the configured one.
+    pub fn adapt_screen_space_error(&mut self) {
+        let now = crate::http::now();
+        let dt = now - self.last_render;
+        self.last_render = now;
+        // egui only repaints on input, long pauses are not slow frames
+        if dt > 0. && dt < 0.5 {
+            self.frame_time = self.frame_time * 0.9 + dt * 0.1;
+        }
+
+        let min = self.settings.maximum_screen_space_error;
+        if !self.settings.adaptive {
+            self.screen_space_error = min;
+            return;
         }
+        let max = self.settings.max_adaptive_screen_space_error.max(min);
+        let pending = self.scheduler.waiting
+            + self.scheduler.in_flight(&self.cache, &self.node_promises, &self.overlays);
+        let sse = if self.frame_time > self.settings.frame_time_budget
+            || pending > self.settings.pending_requests_budget
+        {
+            self.screen_space_error * 1.05
+        } else if self.frame_time < self.settings.frame_time_budget * 0.8
+            && pending < self.settings.pending_requests_budget / 2
+        {
+            self.screen_space_error / 1.02
+        } else {
+            self.screen_space_error
+        };
+        self.screen_space_error = sse.clamp(min, max);
     }
 
-    if !has_rendered {
-        if let Some(t) = cache.get_mut(id) {
-            if let TileContentState::Ready(contents) = &t.content {
-                let mut m = material.clone();
-                for c in contents {
-                    m.texture = Some(c.texture_gpu.clone());
-                    three_d::Geometry::render_with_material(&c.mesh_gpu, &m, camera, lights);
+    /// Draws the selected tiles for `camera`, whose f64 position is `eye`, see [`rtc_camera`].
+    pub fn render(
+        &mut self,
+        camera: &three_d::Camera,
+        eye: DVec3,
+        lights: &[&dyn three_d::Light],
+        show_bounding_boxes: bool,
+    ) -> usize {
+        self.frame += 1;
+        self.adapt_screen_space_error();
+        let mut s = get_view_state(camera, eye);
+        s.maximum_screen_space_error = self.screen_space_error;
+        let mut selection = select_tiles(&self.roots, &self.cache, &s, self.settings.max_level);
+        for id in selection.visible.iter() {
+            if let Some(t) = self.cache.get_mut(id) {
+                t.last_used = self.frame;
+                t.priority = s.load_priority(t);
+            }
+        }
+        for (request, priority) in selection.requests.drain(..) {
+            self.scheduler.request(request, priority);
+        }
+        for id in selection.selected.iter() {
+            if let Some(t) = self.cache.get(id) {
+                if let TileContentState::Ready(contents) = &t.content {
+                    let drape_frame = DrapeFrame::new(t.bv.center);
+                    let drapes = collect_drapes(
+                        &mut self.overlays,
+                        &t.bv,
+                        &drape_frame,
+                        self.frame,
+                        t.priority,
+                        &mut self.scheduler,
+                    );
+                    render_contents(
+                        contents,
+                        &self.material,
+                        self.settings.depth_mode,
+                        &drape_frame,
+                        &drapes,
+                        &rtc_camera(camera, eye, t.bv.center),
+                        lights,
+                    );
+                }
+            }
+        }
+        if show_bounding_boxes {
+            let mut m = self.material.clone();
+            m.texture = None;
+            m.color = three_d::Srgba::WHITE;
+            for id in selection.bounding_boxes.iter() {
+                if let Some(edges) = self.cache.get(id).and_then(|t| t.edges.as_ref()) {
+                    crate::threed_view::render_with_depth_mode(
+                        edges,
+                        &m,
+                        self.settings.depth_mode,
+                        &rtc_camera(camera, eye, edges.origin),
+                        lights,
+                    );
                 }
-                *counter += 1;
-                has_rendered = true;
             }
         }
+        self.cancel_stale();
+        self.scheduler.dispatch(
+            &mut self.cache,
+            &mut self.node_promises,
+            &mut self.overlays,
+            &self.source,
+            &self.counters,
+            self.compressed_target,
+            self.settings.max_requests,
+        );
+        // the selection protects its tiles from eviction
+        self.selection = selection;
+        self.evict();
+        return self.selection.selected.len();
     }
+}
 
-    return (is_visible, has_rendered);
+/// `camera` is the [`rtc_camera`] of the centre `contents` are stored relative to, the
+/// `drapes` are sampled in the same pass.
+fn render_contents(
+    contents: &[TileContentGPU],
+    material: &three_d::ColorMaterial,
+    depth_mode: crate::threed_view::DepthMode,
+    drape_frame: &DrapeFrame,
+    drapes: &[Drape],
+    camera: &three_d::Camera,
+    lights: &[&dyn three_d::Light],
+) {
+    let mut m = material.clone();
+    for c in contents {
+        let mut compressed = None;
+        m.texture = match &c.texture_gpu {
+            Some(TileTextureGPU::Pixels(texture)) => Some(texture.clone()),
+            Some(TileTextureGPU::Compressed(texture)) => {
+                compressed = Some(texture);
+                None
+            }
+            None => None,
+        };
+        m.color = c.color;
+        m.render_states.cull = if c.double_sided {
+            three_d::Cull::None
+        } else {
+            material.render_states.cull
+        };
+        if drapes.is_empty() && compressed.is_none() {
+            crate::threed_view::render_with_depth_mode(&c.mesh_gpu, &m, depth_mode, camera, lights);
+        } else {
+            let material = TileMaterial {
+                base: &m,
+                compressed,
+                depth_mode,
+                frame: drape_frame,
+                drapes,
+            };
+            three_d::Geometry::render_with_material(&c.mesh_gpu, &material, camera, lights);
+        }
+    }
 }
 
 pub struct Tile {
     pub bv: BoundingVolume,
     pub bounding: OrientedBoundingBox,
-    pub edges: crate::lines::LineMesh,
+    /// Bounding box lines, `None` for tiles created without a GL context.
+    pub edges: Option<crate::lines::LineMesh>,
     pub geometric_error: f64,
     pub content: TileContentState,
     pub parent: Option<String>,
     pub children: Vec<String>,
     pub child_options: Vec<String>,
+    pub refine: Refine,
+    /// Cumulative transform of this tile and all its ancestors.
+    pub transform: DMat4,
 
     pub is_visible: bool,
     pub meets_sse: bool,
+    /// Last [`TileCache::frame`] this tile was traversed as visible.
+    pub last_used: u64,
+    /// [`ViewState::load_priority`] of the last traversal, orders requests and uploads.
+    pub priority: f64,
+    /// Data providers of the content, see [`TileCache::attributions`].
+    pub copyright: Vec<String>,
 }
 
 impl Tile {
+    /// Converts decoded `contents` of this tile to meshes and textures on the GPU,
+    /// with a [`PickMesh`] of each if `pick_meshes` is set.
+    pub fn upload(
+        &self,
+        contents: Vec<TileContent>,
+        texture_quality: TextureQuality,
+        pick_meshes: bool,
+        ctx3d: &three_d::Context,
+    ) -> Vec<TileContentGPU> {
+        let mut gpu = vec![];
+        for mut r in contents {
+            if let Some(TileImage::Pixels(texture)) = &mut r.texture {
+                texture_quality.apply(texture);
+            }
+            let mut mesh_gpu = three_d::Mesh::new(&ctx3d, &r.mesh);
+            // relative to the tile centre, see `rtc_camera`
+            let mat = DMat4::from_translation(-self.bv.center) * self.transform * r.mat;
+            mesh_gpu.set_transformation(dglam_to_three_d(&mat));
+            let pick_mesh = pick_meshes.then(|| PickMesh::new(&r.mesh, &mat));
+
+            // a texture the driver rejects leaves the content untextured
+            let texture_gpu = r
+                .texture
+                .as_ref()
+                .and_then(|image| TileTextureGPU::new(ctx3d, image, texture_quality).ok());
+
+            gpu.push(TileContentGPU {
+                mesh_gpu,
+                texture_gpu,
+                color: r.color,
+                double_sided: r.double_sided,
+                gpu_bytes: r.gpu_byte_size(),
+                pick_mesh,
+            });
+        }
+        gpu
+    }
+
     pub fn has_ready_content(&self) -> bool {
         if let TileContentState::Ready(_) = self.content {
             return true;
@@ -297,17 +763,35 @@ impl Tile {
 
     pub fn fill(
         n: &Node,
-        c: &Arc<RestClient>,
+        c: &Arc<dyn TileSource>,
         parent: Option<&String>,
+        parent_refine: Refine,
+        parent_transform: DMat4,
         cache: &mut std::collections::HashMap<String, Tile>,
         roots: &mut Vec<String>,
         is_root: bool,
-        ctx3d: &three_d::Context,
-    ) -> Option<String> {
-        if let Some((uri, mut tile)) = Self::from_node(n, c, parent, ctx3d) {
+        ctx3d: Option<&three_d::Context>,
+    ) -> Vec<String> {
+        let refine = n.refine.unwrap_or(parent_refine);
+        let transform = match &n.transform {
+            Some(t) => parent_transform * DMat4::from_cols_array(t),
+            None => parent_transform,
+        };
+        if let Some((uri, mut tile)) = Self::from_node(n, c, parent, refine, transform, ctx3d) {
             for child in n.children.iter() {
-                if let Some(url) = Self::fill(child, c, Some(&uri), cache, roots, false, ctx3d) {
-                    if url.contains(".glb") {
+                let urls = Self::fill(
+                    child,
+                    c,
+                    Some(&uri),
+                    refine,
+                    transform,
+                    cache,
+                    roots,
+                    false,
+                    ctx3d,
+                );
+                for url in urls {
+                    if c.is_content(&url) {
                         tile.children.push(url);
                     } else {
                         tile.child_options.push(url);
@@ -318,42 +802,51 @@ impl Tile {
             if is_root {
                 roots.push(uri.clone());
             }
-        } else {
-            for child in n.children.iter() {
-                Self::fill(child, c, None, cache, roots, is_root, ctx3d);
-            }
+            return vec![uri];
+        }
+
+        // nodes without tile content get no tile, their children are attached to the closest ancestor
+        let mut urls = vec![];
+        for child in n.children.iter() {
+            urls.extend(Self::fill(
+                child, c, parent, refine, transform, cache, roots, is_root, ctx3d,
+            ));
         }
         if let Some(content) = &n.content {
-            return Some(content.uri.clone());
+            urls.push(content.uri.clone());
         }
-        None
+        urls
     }
     pub fn from_node(
         n: &Node,
-        c: &Arc<RestClient>,
+        c: &Arc<dyn TileSource>,
         parent: Option<&String>,
-        ctx3d: &three_d::Context,
+        refine: Refine,
+        transform: DMat4,
+        ctx3d: Option<&three_d::Context>,
     ) -> Option<(String, Self)> {
         if let Some(content) = &n.content {
-            if content.uri.contains(".glb") {
+            if c.is_content(&content.uri) {
+                let bv = n.bounding.transform(&transform);
                 let tile = Self {
-                    bv: n.bounding.clone(),
-                    edges: n.bounding.as_mesh(ctx3d),
+                    edges: ctx3d.map(|ctx3d| bv.as_mesh(ctx3d)),
                     bounding: OrientedBoundingBox::new(
-                        n.bounding.center,
-                        glam::DMat3::from_cols(
-                            n.bounding.x_axis,
-                            n.bounding.y_axis,
-                            n.bounding.z_axis,
-                        ),
+                        bv.center,
+                        glam::DMat3::from_cols(bv.x_axis, bv.y_axis, bv.z_axis),
                     ),
+                    bv,
                     geometric_error: n.err,
                     content: TileContentState::None,
                     parent: parent.cloned(),
                     children: vec![],
                     child_options: vec![],
+                    refine,
+                    transform,
                     is_visible: false,
                     meets_sse: false,
+                    last_used: 0,
+                    priority: 0.,
+                    copyright: vec![],
                 };
                 return Some((content.uri.clone(), tile));
             }
@@ -362,115 +855,136 @@ impl Tile {
     }
 }
 
-pub fn get_node(
-    path: String,
-    parent: String,
-    c: &Arc<RestClient>,
-) -> poll_promise::Promise<(String, Node)> {
+pub fn get_node(path: String, parent: String, c: &Arc<dyn TileSource>) -> NodeRequest {
     let c = c.clone();
+    let cancel = crate::http::CancelToken::default();
     let (sender, promise) = poll_promise::Promise::new();
+    let uri = path.clone();
+    let token = cancel.clone();
     crate::http::execute(async move {
-        let node = c.get_node(&path).await.unwrap();
-        sender.send((parent, node));
+        if token.is_cancelled() {
+            sender.send(Err(TileSourceError::Other("cancelled".into())));
+            token.finish();
+            return;
+        }
+        sender.send(c.get_node(&path).await);
+        token.finish();
     });
-    return promise;
+    return NodeRequest {
+        parent,
+        uri,
+        promise,
+        cancel,
+    };
 }
 
-pub fn get_contents(path: String, c: &Arc<RestClient>) -> poll_promise::Promise<Vec<TileContent>> {
+pub fn get_contents(
+    path: String,
+    c: &Arc<dyn TileSource>,
+    cancel: crate::http::CancelToken,
+    counters: &Arc<TileLoadCounters>,
+    target: Option<CompressedFormat>,
+) -> poll_promise::Promise<Result<Vec<TileContent>, String>> {
     let (sender, promise) = poll_promise::Promise::new();
 
     let c = c.clone();
+    let counters = counters.clone();
     crate::http::execute(async move {
-        let bytes = c.download(&path).await;
-
-        let glb = gltf::Gltf::from_reader_without_validation(std::io::Cursor::new(bytes)).unwrap();
-        let doc = glb.document;
-        let blob = glb.blob;
-        let buffer_data = gltf::import_buffers(&doc, None, blob).unwrap();
-        let image_data = gltf::import_images(&doc, None, &buffer_data).unwrap();
-
-        let n = doc.nodes().last().unwrap();
-
-        let (p, r, s) = n.transform().decomposed();
-
-        let translation = glam::vec3(p[0], p[1], p[2]);
-        let rotation = glam::quat(r[0], r[1], r[2], r[3]);
-        let scale = glam::vec3(s[0], s[1], s[2]);
-
-        let mat = glam::Mat4::from_scale_rotation_translation(scale, rotation, translation);
+        sender.send(load_contents(&path, &c, &cancel, &counters, target).await);
+        cancel.finish();
+    });
+    return promise;
+}
 
-        let source_mesh = doc.meshes().last().unwrap();
-        let primitives: Vec<_> = source_mesh.primitives().collect();
-        let images: Vec<_> = image_data.iter().collect();
+async fn load_contents(
+    path: &str,
+    c: &Arc<dyn TileSource>,
+    cancel: &crate::http::CancelToken,
+    counters: &TileLoadCounters,
+    target: Option<CompressedFormat>,
+) -> Result<Vec<TileContent>, String> {
+    if cancel.is_cancelled() {
+        return Err("cancelled".into());
+    }
+    let bytes = c.download(path).await?;
+    counters.add_download(bytes.len());
+    // the download itself cannot be interrupted, the scheduler counts it as in flight
+    // until it is finished, see `RequestScheduler::cancelled`
+    if cancel.is_cancelled() {
+        return Err("cancelled".into());
+    }
+    let start = crate::http::now();
+    let contents = c.decode_content(path, bytes, target).await?;
+    counters.add_decode(crate::http::now() - start);
+    Ok(contents)
+}
 
-        let mut contents = vec![];
+/// Decodes the meshes of a glb with the transforms of its node hierarchy, basis textures
+/// are transcoded to `target`.
+pub async fn decode_glb(
+    path: &str,
+    bytes: Vec<u8>,
+    target: Option<CompressedFormat>,
+) -> Result<Vec<TileContent>, String> {
+    let glb = gltf::Gltf::from_reader_without_validation(std::io::Cursor::new(bytes))
+        .map_err(|x| format!("{path}: {x}"))?;
+    let doc = glb.document;
+    let blob = glb.blob;
+    let buffer_data =
+        gltf::import_buffers(&doc, None, blob).map_err(|x| format!("{path}: {x}"))?;
+    let images = load_images(&doc, &buffer_data, target);
+
+    let scene = doc
+        .default_scene()
+        .or_else(|| doc.scenes().next())
+        .ok_or(format!("{path}: no scene"))?;
+
+    // meshes of all nodes with the transform accumulated along the node hierarchy
+    let mut meshes = vec![];
+    let mut stack: Vec<_> = scene.nodes().map(|n| (n, glam::Mat4::IDENTITY)).collect();
+    while let Some((node, parent)) = stack.pop() {
+        let mat = parent * glam::Mat4::from_cols_array_2d(&node.transform().matrix());
+        if let Some(mesh) = node.mesh() {
+            meshes.push((mesh, mat));
+        }
+        for child in node.children() {
+            stack.push((child, mat));
+        }
+    }
 
-        if images.len() >= source_mesh.primitives().len() {
-            for (i, prim) in source_mesh.primitives().enumerate() {
-                contents.push(get_content(&prim, mat, &images[i], &doc, &buffer_data).await);
+    let mut contents = vec![];
+    for (mesh, mat) in meshes {
+        for prim in mesh.primitives() {
+            if prim.mode() != gltf::mesh::Mode::Triangles {
+                continue;
             }
+            contents.push(
+                get_content(&prim, mat, &images, &doc, &buffer_data)
+                    .await
+                    .map_err(|x| format!("{path}: {x}"))?,
+            );
         }
+    }
 
-        sender.send(contents);
-    });
-    return promise;
+    let copyright = doc.as_json().asset.copyright.clone();
+    for c in contents.iter_mut() {
+        c.copyright = copyright.clone();
+    }
+    Ok(contents)
 }
 
 pub async fn get_content(
     prim: &gltf::Primitive<'_>,
     mat: glam::Mat4,
-    image: &gltf::image::Data,
+    images: &[Result<TileImage, String>],
     doc: &gltf::Document,
     buffer_data: &Vec<gltf::buffer::Data>,
-) -> TileContent {
-    // let mut p: draco_gltf_rs::DecodedPrimitive = draco_gltf_rs::decode_draco(
-    //     &prim,
-    //     doc,
-    //     buffer_data,
-    //     &vec![
-    //         draco_gltf_rs::AttrInfo {
-    //             unique_id: 0,
-    //             dim: 3,
-    //             data_type: 9,
-    //         },
-    //         draco_gltf_rs::AttrInfo {
-    //             unique_id: 1,
-    //             dim: 2,
-    //             data_type: 9,
-    //         },
-    //     ],
-    // )
-    // .await
-    // .unwrap();
-
-    // let view = prim.indices().unwrap().view().unwrap();
-    // let indices_bytes =
-    //     &buffer_data[view.buffer().index()][view.offset()..(view.offset() + view.length())];
-    // let indices: Vec<_> = indices_bytes
-    //     .chunks_exact(4)
-    //     .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
-    //     .collect();
-
-    let reader = prim.reader(|buffer| Some(&buffer_data[buffer.index()]));
-    let indices: Vec<_> = reader
-        .read_indices()
-        .expect("no indices found")
-        .into_u32()
-        .collect();
-    let positions: Vec<_> = reader
-        .read_positions()
-        .expect("Primitive has no POSITION attribute")
-        .map(|v| three_d::vec3(v[0], v[1], v[2]))
-        .collect();
-    let texcoords: Vec<_> = reader
-        .read_tex_coords(0)
-        .map(|tc| {
-            // Automatically converts normalized ints to f32
-            tc.into_f32()
-                .map(|uv| three_d::vec2(uv[0], uv[1]))
-                .collect()
-        })
-        .unwrap_or_default();
+) -> Result<TileContent, String> {
+    let material = prim.material();
+    let pbr = material.pbr_metallic_roughness();
+    let base_color = pbr.base_color_texture();
+    let tex_coord = base_color.as_ref().map(|b| b.tex_coord()).unwrap_or_default();
+    let geometry = read_geometry(prim, tex_coord, doc, buffer_data).await?;
 
     let m = glam::Mat4::from_cols_array_2d(&[
         [1.0, 0.0, 0.0, 0.0],
@@ -481,29 +995,32 @@ pub async fn get_content(
     let m_inverse = m.inverse();
 
     let mut mesh = three_d::CpuMesh::default();
-    mesh.indices = three_d::Indices::U32(indices);
-    mesh.positions = three_d::Positions::F32(positions);
-    mesh.uvs = Some(texcoords);
-
-    let mut texture = three_d::CpuTexture::default();
-    texture.width = image.width;
-    texture.height = image.height;
-    texture.wrap_s = three_d::Wrapping::ClampToEdge;
-    texture.wrap_t = three_d::Wrapping::ClampToEdge;
-
-    texture.data = three_d::TextureData::RgbU8(
-        image
-            .pixels
-            .chunks_exact(3)
-            .map(|chunk| [chunk[0], chunk[1], chunk[2]])
-            .collect(),
-    );
-
-    return TileContent {
+    mesh.indices = three_d::Indices::U32(geometry.indices);
+    mesh.positions = three_d::Positions::F32(geometry.positions);
+    if !geometry.uvs.is_empty() {
+        mesh.uvs = Some(geometry.uvs);
+    }
+
+    let texture = match base_color {
+        Some(info) if mesh.uvs.is_some() => {
+            Some(texture_image(&info.texture(), images)?.clone())
+        }
+        _ => None,
+    };
+
+    // the factor is linear, the material colour is sRGB
+    let [r, g, b, a] = pbr.base_color_factor();
+    let srgb = |c: f32| (c.clamp(0., 1.).powf(1. / 2.2) * 255.).round() as u8;
+    let color = three_d::Srgba::new(srgb(r), srgb(g), srgb(b), (a.clamp(0., 1.) * 255.).round() as u8);
+
+    return Ok(TileContent {
         mesh,
         texture,
-        mat: m * mat,
-    };
+        color,
+        double_sided: material.double_sided(),
+        mat: (m * mat).as_dmat4(),
+        copyright: None,
+    });
 }
 
 pub fn obb_in_frustum(planes: &[Plane; 6], obb: &BoundingVolume) -> bool {
@@ -51,7 +51,7 @@ impl OrientedBoundingBox {
             glam::dmat3(
                 transformation.col(0).xyz(),
                 transformation.col(1).xyz(),
-                transformation.col(1).xyz(),
+                transformation.col(2).xyz(),
             ) * self.half_axes,
         )
     }
@@ -0,0 +1,248 @@
+use super::*;
+use crate::threed_view::PickResult;
+
+/// Triangles of an uploaded tile content kept on the CPU for [`TileCache::pick_ray`],
+/// relative to the centre of the tile like the GPU mesh.
+pub struct PickMesh {
+    pub positions: Vec<three_d::Vec3>,
+    pub indices: Vec<u32>,
+}
+
+impl PickMesh {
+    /// Copy of `mesh` transformed by `mat`.
+    pub fn new(mesh: &three_d::CpuMesh, mat: &DMat4) -> Self {
+        let positions = mesh
+            .positions
+            .to_f32()
+            .iter()
+            .map(|p| {
+                let p = mat.transform_point3(DVec3::new(p.x as f64, p.y as f64, p.z as f64));
+                glam_d_vec3_to_three_d(&p)
+            })
+            .collect::<Vec<_>>();
+        let indices = mesh
+            .indices
+            .to_u32()
+            .unwrap_or_else(|| (0..positions.len() as u32).collect());
+        Self { positions, indices }
+    }
+
+    pub fn byte_size(&self) -> usize {
+        self.positions.len() * 12 + self.indices.len() * 4
+    }
+
+    /// Distance along the ray to the nearest triangle, both sides count.
+    pub fn intersect_ray(&self, origin: DVec3, direction: DVec3) -> Option<f64> {
+        let vertex = |i: u32| {
+            let p = self.positions.get(i as usize)?;
+            Some(DVec3::new(p.x as f64, p.y as f64, p.z as f64))
+        };
+        let mut nearest: Option<f64> = None;
+        for t in self.indices.chunks_exact(3) {
+            let (Some(a), Some(b), Some(c)) = (vertex(t[0]), vertex(t[1]), vertex(t[2])) else {
+                continue;
+            };
+            if let Some(d) = intersect_triangle(origin, direction, a, b, c) {
+                if nearest.is_none_or(|n| d < n) {
+                    nearest = Some(d);
+                }
+            }
+        }
+        nearest
+    }
+}
+
+/// Möller–Trumbore ray triangle intersection, returns the distance along the ray.
+fn intersect_triangle(origin: DVec3, direction: DVec3, a: DVec3, b: DVec3, c: DVec3) -> Option<f64> {
+    let ab = b - a;
+    let ac = c - a;
+    let p = direction.cross(ac);
+    let det = ab.dot(p);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let inv_det = 1. / det;
+    let s = origin - a;
+    let u = s.dot(p) * inv_det;
+    if !(0. ..=1.).contains(&u) {
+        return None;
+    }
+    let q = s.cross(ab);
+    let v = direction.dot(q) * inv_det;
+    if v < 0. || u + v > 1. {
+        return None;
+    }
+    let t = ac.dot(q) * inv_det;
+    if t < 0. {
+        return None;
+    }
+    Some(t)
+}
+
+impl BoundingVolume {
+    /// Distance along the ray to where it enters the box, 0 if it starts inside.
+    pub fn intersect_ray(&self, origin: DVec3, direction: DVec3) -> Option<f64> {
+        let mut enter = 0f64;
+        let mut exit = f64::INFINITY;
+        for axis in [self.x_axis, self.y_axis, self.z_axis] {
+            let half = axis.length();
+            if half <= 0. {
+                continue;
+            }
+            let normal = axis / half;
+            let offset = (self.center - origin).dot(normal);
+            let d = direction.dot(normal);
+            if d.abs() < 1e-12 {
+                if offset.abs() > half {
+                    return None;
+                }
+                continue;
+            }
+            let (t1, t2) = ((offset - half) / d, (offset + half) / d);
+            enter = enter.max(t1.min(t2));
+            exit = exit.min(t1.max(t2));
+            if enter > exit {
+                return None;
+            }
+        }
+        Some(enter)
+    }
+}
+
+impl TileCache {
+    /// Nearest hit of the world space ray with the tiles drawn in the last frame, tested
+    /// on the CPU against the triangles kept with [`TileCacheSettings::pick_meshes`].
+    /// Returns the distance along the normalized `direction`.
+    pub fn pick_ray(&self, origin: DVec3, direction: DVec3) -> Option<f64> {
+        let mut candidates = vec![];
+        for id in self.selection.selected.iter() {
+            let Some(t) = self.cache.get(id) else {
+                continue;
+            };
+            if let TileContentState::Ready(contents) = &t.content {
+                if let Some(enter) = t.bv.intersect_ray(origin, direction) {
+                    candidates.push((enter, t, contents));
+                }
+            }
+        }
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut nearest: Option<f64> = None;
+        for (enter, t, contents) in candidates {
+            if nearest.is_some_and(|n| n < enter) {
+                break;
+            }
+            // the meshes are relative to the tile centre
+            let local_origin = origin - t.bv.center;
+            for c in contents {
+                let Some(mesh) = &c.pick_mesh else {
+                    continue;
+                };
+                if let Some(d) = mesh.intersect_ray(local_origin, direction) {
+                    if nearest.is_none_or(|n| d < n) {
+                        nearest = Some(d);
+                    }
+                }
+            }
+        }
+        nearest
+    }
+
+    /// Position of the tile geometry at `pos`, relative to the top left corner of the view
+    /// `camera` renders into. The CPU counterpart of [`crate::threed_view::View::pick`] for
+    /// when the depth buffer is not available. `eye` is the f64 position of `camera`.
+    pub fn pick(
+        &self,
+        camera: &three_d::Camera,
+        eye: DVec3,
+        pos: egui::Pos2,
+    ) -> Option<PickResult> {
+        let (origin, direction) = crate::threed_view::screen_ray(camera, eye, pos);
+        let distance = self.pick_ray(origin, direction)?;
+        Some(PickResult::new(origin + direction * distance, eye))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: DVec3 = DVec3::new(0., 0., 0.);
+    const B: DVec3 = DVec3::new(1., 0., 0.);
+    const C: DVec3 = DVec3::new(0., 1., 0.);
+
+    #[test]
+    fn triangle_hit_from_both_sides() {
+        let down = intersect_triangle(DVec3::new(0.25, 0.25, 2.), -DVec3::Z, A, B, C);
+        assert_eq!(down, Some(2.));
+        let up = intersect_triangle(DVec3::new(0.25, 0.25, -3.), DVec3::Z, A, B, C);
+        assert_eq!(up, Some(3.));
+    }
+
+    #[test]
+    fn triangle_misses() {
+        // beside the hypotenuse
+        assert_eq!(intersect_triangle(DVec3::new(0.75, 0.75, 1.), -DVec3::Z, A, B, C), None);
+        // behind the origin
+        assert_eq!(intersect_triangle(DVec3::new(0.25, 0.25, 1.), DVec3::Z, A, B, C), None);
+        // parallel to the plane
+        assert_eq!(intersect_triangle(DVec3::new(0.25, 0.25, 1.), DVec3::X, A, B, C), None);
+    }
+
+    #[test]
+    fn mesh_returns_the_nearest_triangle() {
+        let v = |x: f32, y: f32, z: f32| three_d::vec3(x, y, z);
+        let mesh = PickMesh {
+            positions: vec![
+                v(0., 0., 0.),
+                v(1., 0., 0.),
+                v(0., 1., 0.),
+                v(0., 0., 5.),
+                v(1., 0., 5.),
+                v(0., 1., 5.),
+            ],
+            // a broken index is skipped
+            indices: vec![0, 1, 2, 3, 4, 5, 0, 1, 9],
+        };
+        let hit = mesh.intersect_ray(DVec3::new(0.25, 0.25, 10.), -DVec3::Z);
+        assert_eq!(hit, Some(5.));
+        assert_eq!(mesh.intersect_ray(DVec3::new(2., 2., 10.), -DVec3::Z), None);
+    }
+
+    fn unit_box(center: DVec3) -> BoundingVolume {
+        BoundingVolume {
+            center,
+            x_axis: DVec3::X,
+            y_axis: DVec3::Y,
+            z_axis: DVec3::Z,
+            region: None,
+        }
+    }
+
+    #[test]
+    fn box_entry_distance() {
+        let bv = unit_box(DVec3::new(10., 0., 0.));
+        assert_eq!(bv.intersect_ray(DVec3::ZERO, DVec3::X), Some(9.));
+        assert_eq!(bv.intersect_ray(DVec3::ZERO, -DVec3::X), None);
+        assert_eq!(bv.intersect_ray(DVec3::new(0., 2., 0.), DVec3::X), None);
+        // starting inside
+        assert_eq!(bv.intersect_ray(DVec3::new(10.5, 0., 0.), DVec3::X), Some(0.));
+    }
+
+    #[test]
+    fn rotated_box() {
+        let d = std::f64::consts::FRAC_1_SQRT_2;
+        let bv = BoundingVolume {
+            center: DVec3::new(10., 0., 0.),
+            x_axis: DVec3::new(d, d, 0.),
+            y_axis: DVec3::new(-d, d, 0.),
+            z_axis: DVec3::Z,
+            region: None,
+        };
+        // the corner of the diamond points at the origin
+        let enter = bv.intersect_ray(DVec3::ZERO, DVec3::X).unwrap();
+        assert!((enter - (10. - std::f64::consts::SQRT_2)).abs() < 1e-9);
+        // passes the axis aligned box around the diamond but not the diamond
+        assert_eq!(bv.intersect_ray(DVec3::new(8.7, 1.3, -10.), DVec3::Z), None);
+    }
+}
@@ -0,0 +1,760 @@
+use super::*;
+use crate::http::BoxFuture;
+
+/// Prefix of the uris [`QuantizedMeshSource::get_node`] expands into the children of a tile.
+const CHILDREN_PREFIX: &str = "quantized-mesh-children:";
+/// Geometric error of level 0, as used by Cesium for geographic tiling.
+const LEVEL_ZERO_GEOMETRIC_ERROR: f64 = 77_067.34;
+/// Heights assumed for tiles whose parent has not been loaded.
+const MIN_HEIGHT: f64 = -11_000.;
+const MAX_HEIGHT: f64 = 9_000.;
+const QUANTIZED_MAX: f64 = 32767.;
+
+const EXTENSION_NORMALS: u8 = 1;
+const EXTENSION_WATER_MASK: u8 = 2;
+/// Extensions [`QuantizedMesh::parse`] reads, servers only send them when asked.
+const SUPPORTED_EXTENSIONS: [&str; 2] = ["octvertexnormals", "watermask"];
+
+/// `layer.json` of a quantized-mesh terrain tileset.
+#[derive(Debug, serde::Deserialize, Clone)]
+pub struct TerrainLayer {
+    /// Url templates with `{z}`, `{x}`, `{y}` and `{version}`.
+    pub tiles: Vec<String>,
+    #[serde(default)]
+    pub version: String,
+    /// Available tile ranges per level.
+    #[serde(default)]
+    pub available: Vec<Vec<TileRange>>,
+    #[serde(default, rename = "maxzoom")]
+    pub max_zoom: Option<u32>,
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    #[serde(default = "default_projection")]
+    pub projection: String,
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+}
+
+fn default_projection() -> String {
+    "EPSG:4326".into()
+}
+
+fn default_scheme() -> String {
+    "tms".into()
+}
+
+/// Inclusive range of tiles of one level.
+#[derive(Debug, serde::Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct TileRange {
+    pub start_x: u32,
+    pub start_y: u32,
+    pub end_x: u32,
+    pub end_y: u32,
+}
+
+impl TerrainLayer {
+    /// Whether the tile `(z, x, y)` exists, `y` counts from the south.
+    pub fn is_available(&self, z: u32, x: u32, y: u32) -> bool {
+        if self.available.is_empty() {
+            return z <= self.max_zoom.unwrap_or(20);
+        }
+        let Some(ranges) = self.available.get(z as usize) else {
+            return false;
+        };
+        ranges
+            .iter()
+            .any(|r| r.start_x <= x && x <= r.end_x && r.start_y <= y && y <= r.end_y)
+    }
+
+    fn has_children(&self, z: u32, x: u32, y: u32) -> bool {
+        (0..4).any(|i| self.is_available(z + 1, x * 2 + i % 2, y * 2 + i / 2))
+    }
+
+    /// `Accept` header of tile requests, asking for the supported extensions of the layer.
+    pub fn accept_header(&self) -> String {
+        let extensions: Vec<&str> = SUPPORTED_EXTENSIONS
+            .into_iter()
+            .filter(|e| self.extensions.iter().any(|x| x == e))
+            .collect();
+        let mesh = match extensions.is_empty() {
+            true => "application/vnd.quantized-mesh".to_string(),
+            false => format!("application/vnd.quantized-mesh;extensions={}", extensions.join("-")),
+        };
+        format!("{mesh},application/octet-stream;q=0.9,*/*;q=0.01")
+    }
+}
+
+/// `[west, south, east, north]` in radians of the geographic tile `(z, x, y)`, level 0 has
+/// two tiles, `y` counts from the south.
+pub fn terrain_tile_rect(z: u32, x: u32, y: u32) -> [f64; 4] {
+    use std::f64::consts::PI;
+    let size = PI / (1u64 << z) as f64;
+    let west = -PI + x as f64 * size;
+    let south = -PI / 2. + y as f64 * size;
+    [west, south, west + size, south + size]
+}
+
+/// Cesium quantized-mesh terrain, served over http(s) or read from a local directory.
+///
+/// Tiles are created lazily: every tile gets a placeholder child that is expanded into its
+/// available children once the traversal refines it, bounded by the heights of the parent.
+pub struct QuantizedMeshSource {
+    /// Url or path of the `layer.json`.
+    pub url: String,
+    pub disk_cache: Option<Arc<DiskCache>>,
+    layer: std::sync::OnceLock<TerrainLayer>,
+    /// Tile coordinates by content uri, needed to place the quantized vertices.
+    coords: std::sync::Mutex<std::collections::HashMap<String, [u32; 3]>>,
+    /// `[min, max]` height of each tile, from its header once it is decoded and inherited
+    /// from the parent before. Bounds the children when the tile is refined.
+    heights: std::sync::Mutex<std::collections::HashMap<[u32; 3], [f64; 2]>>,
+}
+
+impl QuantizedMeshSource {
+    /// `url` may point to a `layer.json` or to the directory containing it.
+    pub fn new(url: impl Into<String>) -> Self {
+        let mut url: String = url.into();
+        let path = url.split('?').next().unwrap_or_default();
+        if !path.ends_with(".json") {
+            if !url.ends_with('/') {
+                url.push('/');
+            }
+            url.push_str("layer.json");
+        }
+        Self {
+            url,
+            disk_cache: None,
+            layer: Default::default(),
+            coords: Default::default(),
+            heights: Default::default(),
+        }
+    }
+
+    /// Caches remote requests, local tilesets are always read directly.
+    pub fn with_disk_cache(mut self, disk_cache: Arc<DiskCache>) -> Self {
+        self.disk_cache = Some(disk_cache);
+        self
+    }
+
+    fn tile_uri(&self, layer: &TerrainLayer, z: u32, x: u32, y: u32) -> String {
+        let row = if layer.scheme == "tms" {
+            y
+        } else {
+            (1 << z) - 1 - y
+        };
+        let template = layer.tiles.first().map(|x| x.as_str()).unwrap_or_default();
+        let uri = template
+            .replace("{z}", &z.to_string())
+            .replace("{x}", &x.to_string())
+            .replace("{y}", &row.to_string())
+            .replace("{version}", &layer.version);
+        let uri = resolve_file_or_url(&self.url, &uri);
+        self.coords.lock().unwrap().insert(uri.clone(), [z, x, y]);
+        uri
+    }
+
+    fn tile_node(&self, layer: &TerrainLayer, z: u32, x: u32, y: u32, heights: [f64; 2]) -> Node {
+        self.heights
+            .lock()
+            .unwrap()
+            .entry([z, x, y])
+            .or_insert(heights);
+        let [west, south, east, north] = terrain_tile_rect(z, x, y);
+        let bounding = BoundingVolume::from_region([west, south, east, north, heights[0], heights[1]]);
+        let err = LEVEL_ZERO_GEOMETRIC_ERROR / (1u64 << z) as f64;
+        let mut children = vec![];
+        if layer.has_children(z, x, y) {
+            children.push(Node {
+                bounding: bounding.clone(),
+                err,
+                content: Some(Content {
+                    uri: format!("{CHILDREN_PREFIX}{z}/{x}/{y}"),
+                }),
+                ..Default::default()
+            });
+        }
+        Node {
+            bounding,
+            children,
+            content: Some(Content {
+                uri: self.tile_uri(layer, z, x, y),
+            }),
+            err,
+            refine: Some(Refine::Replace),
+            ..Default::default()
+        }
+    }
+
+    /// Node without content holding the available children of `(z, x, y)`.
+    async fn children_node(&self, z: u32, x: u32, y: u32) -> Result<Node, TileSourceError> {
+        let layer = self
+            .layer
+            .get()
+            .ok_or_else(|| TileSourceError::Other("layer.json is not loaded".into()))?;
+        let heights = self
+            .heights
+            .lock()
+            .unwrap()
+            .get(&[z, x, y])
+            .copied()
+            .unwrap_or([MIN_HEIGHT, MAX_HEIGHT]);
+
+        let [west, south, east, north] = terrain_tile_rect(z, x, y);
+        let mut node = Node {
+            bounding: BoundingVolume::from_region([west, south, east, north, heights[0], heights[1]]),
+            err: LEVEL_ZERO_GEOMETRIC_ERROR / (1u64 << z) as f64,
+            ..Default::default()
+        };
+        for i in 0..4 {
+            let (cx, cy) = (x * 2 + i % 2, y * 2 + i / 2);
+            if layer.is_available(z + 1, cx, cy) {
+                node.children.push(self.tile_node(layer, z + 1, cx, cy, heights));
+            }
+        }
+        Ok(node)
+    }
+}
+
+impl TileSource for QuantizedMeshSource {
+    fn get_root(&self) -> BoxFuture<'_, Result<Node, TileSourceError>> {
+        Box::pin(async move {
+            let bytes = self.download(&self.url).await?;
+            let layer: TerrainLayer =
+                serde_json::from_slice(&bytes).map_err(|x| format!("layer.json: {x}"))?;
+            if layer.projection != "EPSG:4326" {
+                return Err(format!("unsupported terrain projection {}", layer.projection).into());
+            }
+            let layer = self.layer.get_or_init(|| layer);
+
+            use std::f64::consts::PI;
+            let mut root = Node {
+                bounding: BoundingVolume::from_region([-PI, -PI / 2., PI, PI / 2., MIN_HEIGHT, MAX_HEIGHT]),
+                err: LEVEL_ZERO_GEOMETRIC_ERROR * 2.,
+                ..Default::default()
+            };
+            for x in 0..2 {
+                if layer.is_available(0, x, 0) {
+                    root.children
+                        .push(self.tile_node(layer, 0, x, 0, [MIN_HEIGHT, MAX_HEIGHT]));
+                }
+            }
+            Ok(root)
+        })
+    }
+
+    fn download<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Result<Vec<u8>, TileSourceError>> {
+        Box::pin(async move {
+            let accept = match self.layer.get() {
+                Some(layer) if uri != self.url => layer.accept_header(),
+                _ => "application/json".to_string(),
+            };
+            download_file_or_url_with_headers(
+                uri,
+                self.disk_cache.as_deref(),
+                &[("Accept", accept.as_str())],
+            )
+            .await
+        })
+    }
+
+    fn resolve_uri(&self, base: &str, uri: &str) -> String {
+        resolve_file_or_url(base, uri)
+    }
+
+    fn is_content(&self, uri: &str) -> bool {
+        !uri.starts_with(CHILDREN_PREFIX)
+    }
+
+    fn decode_content<'a>(
+        &'a self,
+        uri: &'a str,
+        bytes: Vec<u8>,
+        _target: Option<CompressedFormat>,
+    ) -> BoxFuture<'a, Result<Vec<TileContent>, String>> {
+        Box::pin(async move {
+            let [z, x, y] = self
+                .coords
+                .lock()
+                .unwrap()
+                .get(uri)
+                .copied()
+                .ok_or(format!("{uri}: unknown terrain tile"))?;
+            let mesh = QuantizedMesh::parse(&decompress(bytes)?).map_err(|x| format!("{uri}: {x}"))?;
+            self.heights.lock().unwrap().insert(
+                [z, x, y],
+                [mesh.header.min_height as f64, mesh.header.max_height as f64],
+            );
+            Ok(vec![mesh.to_content(z, x, y)])
+        })
+    }
+
+    fn get_node<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Result<Node, TileSourceError>> {
+        Box::pin(async move {
+            let coords: Vec<u32> = uri
+                .strip_prefix(CHILDREN_PREFIX)
+                .ok_or(format!("{uri}: not a terrain tile"))?
+                .split('/')
+                .filter_map(|x| x.parse().ok())
+                .collect();
+            let [z, x, y] = coords[..] else {
+                return Err(format!("{uri}: not a terrain tile").into());
+            };
+            self.children_node(z, x, y).await
+        })
+    }
+}
+
+/// Terrain tiles of static mirrors are often stored gzipped.
+fn decompress(bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !bytes.starts_with(&[0x1f, 0x8b]) {
+        return Ok(bytes);
+    }
+    use std::io::Read;
+    let mut data = vec![];
+    flate2::read::GzDecoder::new(&bytes[..])
+        .read_to_end(&mut data)
+        .map_err(|x| format!("gzip: {x}"))?;
+    Ok(data)
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or("unexpected end of data")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn align(&mut self, n: usize) {
+        self.pos = self.pos.div_ceil(n) * n;
+    }
+
+    fn indices(&mut self, count: usize, wide: bool) -> Result<Vec<u32>, String> {
+        (0..count)
+            .map(|_| match wide {
+                true => self.u32(),
+                false => self.u16().map(|x| x as u32),
+            })
+            .collect()
+    }
+}
+
+pub struct QuantizedMeshHeader {
+    /// Earth-centered earth-fixed centre of the tile.
+    pub center: DVec3,
+    pub min_height: f32,
+    pub max_height: f32,
+}
+
+impl QuantizedMeshHeader {
+    fn read(r: &mut ByteReader) -> Result<Self, String> {
+        let center = glam::dvec3(r.f64()?, r.f64()?, r.f64()?);
+        let min_height = r.f32()?;
+        let max_height = r.f32()?;
+        // bounding sphere and horizon occlusion point
+        r.take(7 * 8)?;
+        Ok(Self {
+            center,
+            min_height,
+            max_height,
+        })
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        Self::read(&mut ByteReader { bytes, pos: 0 })
+    }
+}
+
+/// Decoded quantized-mesh tile, see
+/// <https://github.com/CesiumGS/quantized-mesh>.
+pub struct QuantizedMesh {
+    pub header: QuantizedMeshHeader,
+    /// `(u, v, height)` in `0..=32767`.
+    pub vertices: Vec<[u16; 3]>,
+    pub indices: Vec<u32>,
+    /// Vertices on the west, south, east and north edge.
+    pub edges: [Vec<u32>; 4],
+    /// Earth-centered earth-fixed normals of the `octvertexnormals` extension.
+    pub normals: Option<Vec<DVec3>>,
+    /// 256x256 grid from the north-west corner, 255 is water. A single value covers the tile.
+    pub water_mask: Option<Vec<u8>>,
+}
+
+fn zig_zag(v: u16) -> i32 {
+    (v >> 1) as i32 ^ -((v & 1) as i32)
+}
+
+fn oct_decode(x: u8, y: u8) -> DVec3 {
+    let x = x as f64 / 255. * 2. - 1.;
+    let y = y as f64 / 255. * 2. - 1.;
+    let z = 1. - x.abs() - y.abs();
+    let (x, y) = if z < 0. {
+        ((1. - y.abs()) * x.signum(), (1. - x.abs()) * y.signum())
+    } else {
+        (x, y)
+    };
+    glam::dvec3(x, y, z).normalize_or_zero()
+}
+
+impl QuantizedMesh {
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let r = &mut ByteReader { bytes, pos: 0 };
+        let header = QuantizedMeshHeader::read(r)?;
+
+        let count = r.u32()? as usize;
+        let mut channels = [vec![], vec![], vec![]];
+        for channel in channels.iter_mut() {
+            let mut value = 0i32;
+            for _ in 0..count {
+                value += zig_zag(r.u16()?);
+                channel.push(value as u16);
+            }
+        }
+        let vertices = (0..count)
+            .map(|i| [channels[0][i], channels[1][i], channels[2][i]])
+            .collect();
+
+        let wide = count > 65536;
+        r.align(if wide { 4 } else { 2 });
+        let triangles = r.u32()? as usize;
+        let mut highest = 0;
+        let mut indices = r.indices(triangles * 3, wide)?;
+        for i in indices.iter_mut() {
+            let code = *i;
+            *i = highest.checked_sub(code).ok_or("invalid index")?;
+            if code == 0 {
+                highest += 1;
+            }
+        }
+        if indices.iter().any(|&i| i as usize >= count) {
+            return Err("index out of range".into());
+        }
+
+        let mut edges: [Vec<u32>; 4] = Default::default();
+        for edge in edges.iter_mut() {
+            let n = r.u32()? as usize;
+            *edge = r.indices(n, wide)?;
+            if edge.iter().any(|&i| i as usize >= count) {
+                return Err("edge index out of range".into());
+            }
+        }
+
+        let mut normals = None;
+        let mut water_mask = None;
+        while r.pos + 5 <= bytes.len() {
+            let id = r.u8()?;
+            let len = r.u32()? as usize;
+            let data = r.take(len)?;
+            match id {
+                EXTENSION_NORMALS => {
+                    normals = Some(data.chunks_exact(2).map(|c| oct_decode(c[0], c[1])).collect())
+                }
+                EXTENSION_WATER_MASK => water_mask = Some(data.to_vec()),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            header,
+            vertices,
+            indices,
+            edges,
+            normals,
+            water_mask,
+        })
+    }
+
+    fn is_water(&self, u: u16, v: u16) -> bool {
+        match self.water_mask.as_deref() {
+            Some([value]) => *value > 127,
+            Some(mask) if mask.len() == 256 * 256 => {
+                let column = (u as usize * 255) / 32767;
+                let row = 255 - (v as usize * 255) / 32767;
+                mask[row * 256 + column] > 127
+            }
+            _ => false,
+        }
+    }
+
+    /// Mesh of the tile `(z, x, y)` relative to its centre. Since [`three_d::ColorMaterial`]
+    /// is unlit, a hillshade and the water mask are baked into the vertex colours. Skirts
+    /// along the edges hide the cracks between tiles of different levels.
+    pub fn to_content(&self, z: u32, x: u32, y: u32) -> TileContent {
+        let [west, south, east, north] = terrain_tile_rect(z, x, y);
+        let (min_h, max_h) = (self.header.min_height as f64, self.header.max_height as f64);
+        let center = self.header.center;
+
+        let mut positions: Vec<DVec3> = self
+            .vertices
+            .iter()
+            .map(|[u, v, h]| {
+                let lon = west + (east - west) * *u as f64 / QUANTIZED_MAX;
+                let lat = south + (north - south) * *v as f64 / QUANTIZED_MAX;
+                let height = min_h + (max_h - min_h) * *h as f64 / QUANTIZED_MAX;
+                latlon_to_xyz(lat.to_degrees(), lon.to_degrees(), height)
+            })
+            .collect();
+        let mut normals = match &self.normals {
+            Some(n) if n.len() == positions.len() => n.clone(),
+            _ => vertex_normals(&positions, &self.indices),
+        };
+
+        // light from the north-west, 45° above the horizon
+        let (lat, lon) = ((south + north) / 2., (west + east) / 2.);
+        let up = glam::dvec3(lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin());
+        let east_axis = glam::dvec3(-lon.sin(), lon.cos(), 0.);
+        let north_axis = up.cross(east_axis);
+        let light = (up * 0.707 - east_axis * 0.5 + north_axis * 0.5).normalize();
+        let mut colors: Vec<_> = self
+            .vertices
+            .iter()
+            .zip(normals.iter())
+            .map(|([u, v, _], n)| {
+                let shade = 0.35 + 0.65 * n.dot(light).max(0.);
+                let base: [f64; 3] = match self.is_water(*u, *v) {
+                    true => [70., 110., 170.],
+                    false => [205., 195., 165.],
+                };
+                three_d::Srgba::new_opaque(
+                    (base[0] * shade) as u8,
+                    (base[1] * shade) as u8,
+                    (base[2] * shade) as u8,
+                )
+            })
+            .collect();
+
+        let mut indices = self.indices.clone();
+        let skirt = LEVEL_ZERO_GEOMETRIC_ERROR / (1u64 << z) as f64 * 5.;
+        // west and east edges run along v, south and north along u
+        for (e, edge) in self.edges.iter().enumerate() {
+            let mut edge = edge.clone();
+            edge.sort_by_key(|&i| self.vertices[i as usize][if e % 2 == 0 { 1 } else { 0 }]);
+            for pair in edge.windows(2) {
+                let (a, b) = (pair[0], pair[1]);
+                let a_low = positions.len() as u32;
+                for i in [a, b] {
+                    let p = positions[i as usize];
+                    positions.push(p - p.normalize() * skirt);
+                    normals.push(normals[i as usize]);
+                    colors.push(colors[i as usize]);
+                }
+                indices.extend_from_slice(&[a, b, a_low + 1, a, a_low + 1, a_low]);
+            }
+        }
+
+        let mesh = three_d::CpuMesh {
+            positions: three_d::Positions::F32(
+                positions
+                    .iter()
+                    .map(|p| glam_d_vec3_to_three_d(&(*p - center)))
+                    .collect(),
+            ),
+            indices: three_d::Indices::U32(indices),
+            normals: Some(normals.iter().map(|n| glam_d_vec3_to_three_d(n)).collect()),
+            colors: Some(colors),
+            ..Default::default()
+        };
+
+        TileContent {
+            mesh,
+            texture: None,
+            color: three_d::Srgba::WHITE,
+            // skirts are seen from both sides
+            double_sided: true,
+            mat: DMat4::from_translation(center),
+            copyright: None,
+        }
+    }
+}
+
+/// Area weighted vertex normals, for tiles without the normals extension.
+fn vertex_normals(positions: &[DVec3], indices: &[u32]) -> Vec<DVec3> {
+    let mut normals = vec![DVec3::ZERO; positions.len()];
+    for t in indices.chunks_exact(3) {
+        let [a, b, c] = [t[0] as usize, t[1] as usize, t[2] as usize];
+        let n = (positions[b] - positions[a]).cross(positions[c] - positions[a]);
+        normals[a] += n;
+        normals[b] += n;
+        normals[c] += n;
+    }
+    normals
+        .iter()
+        .zip(positions)
+        .map(|(n, p)| {
+            // faces are counter-clockwise seen from above
+            let n = n.normalize_or_zero();
+            if n.dot(*p) < 0. { -n } else { n }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zig_zag_encode(v: i32) -> u16 {
+        ((v << 1) ^ (v >> 31)) as u16
+    }
+
+    /// Encodes a tile with 16 bit indices the way terrain servers do.
+    fn encode(mesh: &QuantizedMesh, extensions: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut b = vec![];
+        let header = &mesh.header;
+        for v in [header.center.x, header.center.y, header.center.z] {
+            b.extend_from_slice(&v.to_le_bytes());
+        }
+        b.extend_from_slice(&header.min_height.to_le_bytes());
+        b.extend_from_slice(&header.max_height.to_le_bytes());
+        // bounding sphere and horizon occlusion point
+        b.extend_from_slice(&[0; 7 * 8]);
+
+        b.extend_from_slice(&(mesh.vertices.len() as u32).to_le_bytes());
+        for channel in 0..3 {
+            let mut previous = 0i32;
+            for vertex in mesh.vertices.iter() {
+                let value = vertex[channel] as i32;
+                b.extend_from_slice(&zig_zag_encode(value - previous).to_le_bytes());
+                previous = value;
+            }
+        }
+
+        if b.len() % 2 != 0 {
+            b.push(0);
+        }
+        b.extend_from_slice(&(mesh.indices.len() as u32 / 3).to_le_bytes());
+        let mut highest = 0;
+        for &i in mesh.indices.iter() {
+            b.extend_from_slice(&((highest - i) as u16).to_le_bytes());
+            if i == highest {
+                highest += 1;
+            }
+        }
+
+        for edge in mesh.edges.iter() {
+            b.extend_from_slice(&(edge.len() as u32).to_le_bytes());
+            for &i in edge {
+                b.extend_from_slice(&(i as u16).to_le_bytes());
+            }
+        }
+
+        for (id, data) in extensions {
+            b.push(*id);
+            b.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            b.extend_from_slice(data);
+        }
+        b
+    }
+
+    /// Two triangles covering the tile.
+    fn quad() -> QuantizedMesh {
+        QuantizedMesh {
+            header: QuantizedMeshHeader {
+                center: glam::dvec3(4_000_000.5, 1_000_000.25, 4_800_000.125),
+                min_height: -12.5,
+                max_height: 830.,
+            },
+            vertices: vec![[0, 0, 0], [32767, 0, 100], [0, 32767, 20000], [32767, 32767, 32767]],
+            indices: vec![0, 1, 2, 2, 1, 3],
+            edges: [vec![0, 2], vec![0, 1], vec![1, 3], vec![2, 3]],
+            normals: None,
+            water_mask: None,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mesh = quad();
+        let parsed = QuantizedMesh::parse(&encode(&mesh, &[])).unwrap();
+        assert_eq!(parsed.header.center, mesh.header.center);
+        assert_eq!(parsed.header.min_height, mesh.header.min_height);
+        assert_eq!(parsed.header.max_height, mesh.header.max_height);
+        assert_eq!(parsed.vertices, mesh.vertices);
+        assert_eq!(parsed.indices, mesh.indices);
+        assert_eq!(parsed.edges, mesh.edges);
+        assert!(parsed.normals.is_none());
+        assert!(parsed.water_mask.is_none());
+    }
+
+    #[test]
+    fn round_trip_with_extensions() {
+        let mesh = quad();
+        let extensions = [
+            (EXTENSION_NORMALS, vec![255, 0, 0, 255, 255, 255, 0, 0]),
+            (EXTENSION_WATER_MASK, vec![255]),
+            // unknown extensions are skipped
+            (7, vec![1, 2, 3]),
+        ];
+        let parsed = QuantizedMesh::parse(&encode(&mesh, &extensions)).unwrap();
+        assert_eq!(parsed.vertices, mesh.vertices);
+        let normals = parsed.normals.as_ref().unwrap();
+        assert_eq!(normals.len(), 4);
+        assert!(normals.iter().all(|n| (n.length() - 1.).abs() < 1e-9));
+        assert!(normals[0].distance(glam::dvec3(0., 0., -1.)) < 1e-9);
+        assert_eq!(parsed.water_mask.as_deref(), Some(&[255][..]));
+        assert!(parsed.is_water(0, 0));
+    }
+
+    #[test]
+    fn header_only() {
+        let bytes = encode(&quad(), &[]);
+        let header = QuantizedMeshHeader::parse(&bytes[..88]).unwrap();
+        assert_eq!(header.max_height, 830.);
+        assert!(QuantizedMeshHeader::parse(&bytes[..87]).is_err());
+    }
+
+    #[test]
+    fn truncated_tiles_fail() {
+        let bytes = encode(&quad(), &[]);
+        assert!(QuantizedMesh::parse(&bytes[..bytes.len() - 3]).is_err());
+        assert!(QuantizedMesh::parse(&bytes[..100]).is_err());
+    }
+
+    #[test]
+    fn indices_above_the_high_water_mark_fail() {
+        let mut bytes = encode(&quad(), &[]);
+        // first index of the first triangle, 88 byte header, 4 vertices and the count
+        let first = 88 + 4 + 4 * 6 + 4;
+        bytes[first..first + 2].copy_from_slice(&5u16.to_le_bytes());
+        assert!(QuantizedMesh::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn indices_beyond_the_vertices_fail() {
+        // the high water mark reaches 4 with only 3 vertices
+        let mut mesh = quad();
+        mesh.vertices.truncate(3);
+        assert!(QuantizedMesh::parse(&encode(&mesh, &[])).is_err());
+
+        let mut mesh = quad();
+        mesh.edges[2] = vec![1, 9];
+        assert!(QuantizedMesh::parse(&encode(&mesh, &[])).is_err());
+    }
+}
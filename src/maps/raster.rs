@@ -0,0 +1,356 @@
+use super::*;
+use crate::http::BoxFuture;
+
+/// Prefix of the uris [`RasterSource::get_node`] expands into the children of a tile.
+const CHILDREN_PREFIX: &str = "raster-children:";
+/// Prefix of the content uris, [`RasterSource::download`] expands them into the layer's url.
+const TILE_PREFIX: &str = "raster:";
+/// Grid cells per tile side.
+const SEGMENTS: u32 = 16;
+const TILE_PIXELS: f64 = 256.;
+const EQUATOR: f64 = 40_075_016.686;
+const EARTH_RADIUS: f64 = 6_378_137.;
+
+/// Where the images of a [`RasterSource`] come from. All of them use the web mercator
+/// quadtree of OSM-style slippy maps.
+#[derive(Debug, Clone)]
+pub enum RasterLayer {
+    /// Url or path template with `{z}`, `{x}` and `{y}`, rows counted from the north.
+    /// The WMTS placeholders `{TileMatrix}`, `{TileCol}` and `{TileRow}` work as well, `{s}`
+    /// is replaced by one of the [`RasterSource::subdomains`].
+    Xyz(String),
+    /// Like [`RasterLayer::Xyz`] with rows counted from the south.
+    Tms(String),
+    /// Local MBTiles file, not supported on the web.
+    MbTiles(std::path::PathBuf),
+}
+
+/// Latitude in radians of the row `y` (fractional, from the north) of level `z`.
+pub fn mercator_lat(y: f64, z: u32) -> f64 {
+    let n = std::f64::consts::PI * (1. - 2. * y / (1u64 << z) as f64);
+    n.sinh().atan()
+}
+
+/// `[west, south, east, north]` in radians of the slippy map tile `(z, x, y)`.
+pub fn raster_tile_rect(z: u32, x: u32, y: u32) -> [f64; 4] {
+    let size = std::f64::consts::TAU / (1u64 << z) as f64;
+    let west = -std::f64::consts::PI + x as f64 * size;
+    [west, mercator_lat(y as f64 + 1., z), west + size, mercator_lat(y as f64, z)]
+}
+
+/// WGS84 ellipsoid textured with raster map tiles, a base globe without any 3D tiles.
+///
+/// Every tile is a grid on the ellipsoid with skirts that hide the cracks to neighbours of
+/// other levels. Like [`QuantizedMeshSource`] the quadtree is expanded lazily, so the
+/// [`TileCache`] selects the levels from the screen space error of the texels.
+pub struct RasterSource {
+    pub layer: RasterLayer,
+    /// Deepest level that is requested.
+    pub max_zoom: u32,
+    pub disk_cache: Option<Arc<DiskCache>>,
+    /// Shown by [`TileCache::attributions`], e.g. `© OpenStreetMap contributors`.
+    pub attribution: Option<String>,
+    /// Values of `{s}` in the url template, a tile always uses the same one so it stays
+    /// cached.
+    pub subdomains: Vec<String>,
+    /// Sent with remote requests, e.g. the identifying `User-Agent` the OSM tile usage
+    /// policy asks for. Browsers send their own user agent.
+    pub headers: Vec<(String, String)>,
+    #[cfg(not(target_arch = "wasm32"))]
+    mbtiles: std::sync::OnceLock<Result<std::sync::Mutex<rusqlite::Connection>, String>>,
+}
+
+impl RasterSource {
+    pub fn new(layer: RasterLayer) -> Self {
+        Self {
+            layer,
+            max_zoom: 19,
+            disk_cache: None,
+            attribution: None,
+            subdomains: ["a", "b", "c"].map(String::from).to_vec(),
+            headers: vec![],
+            #[cfg(not(target_arch = "wasm32"))]
+            mbtiles: Default::default(),
+        }
+    }
+
+    /// Caches remote requests, local files are always read directly.
+    pub fn with_disk_cache(mut self, disk_cache: Arc<DiskCache>) -> Self {
+        self.disk_cache = Some(disk_cache);
+        self
+    }
+
+    pub fn with_max_zoom(mut self, max_zoom: u32) -> Self {
+        self.max_zoom = max_zoom;
+        self
+    }
+
+    pub fn with_attribution(mut self, attribution: impl Into<String>) -> Self {
+        self.attribution = Some(attribution.into());
+        self
+    }
+
+    pub fn with_subdomains(
+        mut self,
+        subdomains: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.subdomains = subdomains.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Content uri of the tile `(z, x, y)`, holds the coordinates so none have to be kept.
+    pub(crate) fn tile_uri(&self, z: u32, x: u32, y: u32) -> String {
+        format!("{TILE_PREFIX}{z}/{x}/{y}")
+    }
+
+    /// Url or path of the tile `(z, x, y)` from `template`, see [`RasterLayer::Xyz`].
+    fn tile_url(&self, template: &str, z: u32, x: u32, y: u32) -> String {
+        let row = match &self.layer {
+            RasterLayer::Tms(_) => (1 << z) - 1 - y,
+            _ => y,
+        };
+        let mut url = template
+            .replace("{z}", &z.to_string())
+            .replace("{x}", &x.to_string())
+            .replace("{y}", &row.to_string())
+            .replace("{TileMatrix}", &z.to_string())
+            .replace("{TileCol}", &x.to_string())
+            .replace("{TileRow}", &row.to_string());
+        if !self.subdomains.is_empty() {
+            let subdomain = &self.subdomains[(x as usize + y as usize) % self.subdomains.len()];
+            url = url.replace("{s}", subdomain);
+        }
+        url
+    }
+
+    fn geometric_error(z: u32) -> f64 {
+        // refined once a texel covers more than about 2 pixels at the default error of 16
+        EQUATOR / TILE_PIXELS / (1u64 << z) as f64 * 8.
+    }
+
+    fn bounding(z: u32, x: u32, y: u32) -> BoundingVolume {
+        let [west, south, east, north] = raster_tile_rect(z, x, y);
+        BoundingVolume::from_region([west, south, east, north, -10., 10.])
+    }
+
+    fn tile_node(&self, z: u32, x: u32, y: u32) -> Node {
+        let bounding = Self::bounding(z, x, y);
+        let err = Self::geometric_error(z);
+        let mut children = vec![];
+        if z < self.max_zoom {
+            children.push(Node {
+                bounding: bounding.clone(),
+                err,
+                content: Some(Content {
+                    uri: format!("{CHILDREN_PREFIX}{z}/{x}/{y}"),
+                }),
+                ..Default::default()
+            });
+        }
+        Node {
+            bounding,
+            children,
+            content: Some(Content {
+                uri: self.tile_uri(z, x, y),
+            }),
+            err,
+            refine: Some(Refine::Replace),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn read_mbtiles(&self, path: &std::path::Path, [z, x, y]: [u32; 3]) -> Result<Vec<u8>, String> {
+        let connection = self
+            .mbtiles
+            .get_or_init(|| {
+                rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+                    .map(std::sync::Mutex::new)
+                    .map_err(|x| format!("{}: {x}", path.display()))
+            })
+            .as_ref()
+            .map_err(|x| x.clone())?;
+        // MBTiles count the rows from the south
+        let row = (1 << z) - 1 - y;
+        connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                [z, x, row],
+                |r| r.get(0),
+            )
+            .map_err(|e| format!("{}: {z}/{x}/{y}: {e}", path.display()))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn read_mbtiles(&self, _path: &std::path::Path, _tile: [u32; 3]) -> Result<Vec<u8>, String> {
+        Err("MBTiles are not supported on the web".into())
+    }
+}
+
+/// Parses `z/x/y`.
+fn parse_coords(s: &str) -> Option<[u32; 3]> {
+    let coords: Vec<u32> = s.split('/').filter_map(|x| x.parse().ok()).collect();
+    coords.try_into().ok()
+}
+
+/// Coordinates of a [`RasterSource::tile_uri`].
+fn tile_coords(uri: &str) -> Result<[u32; 3], String> {
+    uri.strip_prefix(TILE_PREFIX)
+        .and_then(parse_coords)
+        .ok_or(format!("{uri}: unknown raster tile"))
+}
+
+impl TileSource for RasterSource {
+    fn get_root(&self) -> BoxFuture<'_, Result<Node, TileSourceError>> {
+        Box::pin(async move {
+            let root = self.tile_node(0, 0, 0);
+            Ok(Node {
+                bounding: root.bounding.clone(),
+                err: root.err * 2.,
+                children: vec![root],
+                ..Default::default()
+            })
+        })
+    }
+
+    fn download<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Result<Vec<u8>, TileSourceError>> {
+        Box::pin(async move {
+            let [z, x, y] = tile_coords(uri)?;
+            match &self.layer {
+                RasterLayer::MbTiles(path) => Ok(self.read_mbtiles(path, [z, x, y])?),
+                RasterLayer::Xyz(template) | RasterLayer::Tms(template) => {
+                    let headers: Vec<_> = self
+                        .headers
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.as_str()))
+                        .collect();
+                    download_file_or_url_with_headers(
+                        &self.tile_url(template, z, x, y),
+                        self.disk_cache.as_deref(),
+                        &headers,
+                    )
+                    .await
+                }
+            }
+        })
+    }
+
+    fn resolve_uri(&self, base: &str, uri: &str) -> String {
+        resolve_file_or_url(base, uri)
+    }
+
+    fn is_content(&self, uri: &str) -> bool {
+        !uri.starts_with(CHILDREN_PREFIX)
+    }
+
+    fn decode_content<'a>(
+        &'a self,
+        uri: &'a str,
+        bytes: Vec<u8>,
+        _target: Option<CompressedFormat>,
+    ) -> BoxFuture<'a, Result<Vec<TileContent>, String>> {
+        Box::pin(async move {
+            let [z, x, y] = tile_coords(uri)?;
+            let texture = decode_image(&bytes).map_err(|x| format!("{uri}: {x}"))?;
+            let mut content = globe_tile(z, x, y);
+            content.texture = Some(TileImage::Pixels(texture));
+            content.copyright = self.attribution.clone();
+            Ok(vec![content])
+        })
+    }
+
+    fn get_node<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Result<Node, TileSourceError>> {
+        Box::pin(async move {
+            let [z, x, y] = uri
+                .strip_prefix(CHILDREN_PREFIX)
+                .and_then(parse_coords)
+                .ok_or(format!("{uri}: not a raster tile"))?;
+            let mut node = Node {
+                bounding: Self::bounding(z, x, y),
+                err: Self::geometric_error(z),
+                ..Default::default()
+            };
+            for i in 0..4 {
+                node.children
+                    .push(self.tile_node(z + 1, x * 2 + i % 2, y * 2 + i / 2));
+            }
+            Ok(node)
+        })
+    }
+}
+
+/// Untextured ellipsoid grid of the slippy map tile `(z, x, y)` relative to its centre.
+/// The uvs are linear in the mercator rows, so they match the raster image.
+pub fn globe_tile(z: u32, x: u32, y: u32) -> TileContent {
+    let [west, _, east, _] = raster_tile_rect(z, x, y);
+    let n = SEGMENTS;
+    let mut positions = vec![];
+    let mut uvs = vec![];
+    for j in 0..=n {
+        let lat = mercator_lat(y as f64 + j as f64 / n as f64, z);
+        for i in 0..=n {
+            let lon = west + (east - west) * i as f64 / n as f64;
+            positions.push(latlon_to_xyz(lat.to_degrees(), lon.to_degrees(), 0.));
+            uvs.push(three_d::vec2(i as f32 / n as f32, j as f32 / n as f32));
+        }
+    }
+    let index = |i: u32, j: u32| j * (n + 1) + i;
+    let mut indices = vec![];
+    for j in 0..n {
+        for i in 0..n {
+            let (a, b, c, d) = (index(i, j), index(i + 1, j), index(i, j + 1), index(i + 1, j + 1));
+            // counter-clockwise seen from outside
+            indices.extend_from_slice(&[a, c, b, b, c, d]);
+        }
+    }
+
+    // the flat cells sag below the ellipsoid by up to len²/8r, the skirts cover twice that
+    let cell = EARTH_RADIUS * (east - west) / n as f64;
+    let skirt = cell * cell / (4. * EARTH_RADIUS) + 1.;
+    let borders = [
+        (0..=n).map(|i| index(i, 0)).collect::<Vec<_>>(),
+        (0..=n).map(|i| index(i, n)).collect(),
+        (0..=n).map(|j| index(0, j)).collect(),
+        (0..=n).map(|j| index(n, j)).collect(),
+    ];
+    for border in borders {
+        for pair in border.windows(2) {
+            let low = positions.len() as u32;
+            for i in [pair[0], pair[1]] {
+                let p = positions[i as usize];
+                positions.push(p - p.normalize() * skirt);
+                uvs.push(uvs[i as usize]);
+            }
+            indices.extend_from_slice(&[pair[0], pair[1], low + 1, pair[0], low + 1, low]);
+        }
+    }
+
+    let center = positions.iter().sum::<DVec3>() / positions.len() as f64;
+    let mesh = three_d::CpuMesh {
+        positions: three_d::Positions::F32(
+            positions
+                .iter()
+                .map(|p| glam_d_vec3_to_three_d(&(*p - center)))
+                .collect(),
+        ),
+        indices: three_d::Indices::U32(indices),
+        uvs: Some(uvs),
+        ..Default::default()
+    };
+    TileContent {
+        mesh,
+        texture: None,
+        color: three_d::Srgba::WHITE,
+        // skirts are seen from both sides
+        double_sided: true,
+        mat: DMat4::from_translation(center),
+        copyright: None,
+    }
+}
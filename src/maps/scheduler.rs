@@ -0,0 +1,169 @@
+use super::*;
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum TileRequest {
+    /// Content of the tile with this id.
+    Content(String),
+    /// External tileset json `uri` referenced by the tile `parent`.
+    Node { parent: String, uri: String },
+    /// Raster tile `[z, x, y]` of the overlay whose [`DrapeLayer::id`] is `layer`.
+    Overlay { layer: u64, tile: [u32; 3] },
+}
+
+/// Collects the load requests of one traversal and starts the most important ones.
+///
+/// The queue is rebuilt every frame, so priorities always reflect the current camera and
+/// requests for tiles that left the view are dropped before they are started.
+#[derive(Default)]
+pub struct RequestScheduler {
+    pub queue: std::collections::HashMap<TileRequest, f64>,
+    /// Requests that could not be started in the last [`RequestScheduler::dispatch`].
+    pub waiting: usize,
+    /// Failed external tileset requests by uri.
+    pub node_failures: std::collections::HashMap<String, TileFailure>,
+    /// Implicit tiling subtrees that are not loaded yet by uri.
+    pub subtrees: std::collections::HashMap<String, Arc<SubtreeRequest>>,
+    /// Cancelled requests whose download is still running, they keep their connection
+    /// and count as in flight until they are finished.
+    pub cancelled: Vec<crate::http::CancelToken>,
+}
+
+impl RequestScheduler {
+    /// Queues `request`, a request queued several times keeps its highest priority.
+    pub fn request(&mut self, request: TileRequest, priority: f64) {
+        let p = self.queue.entry(request).or_insert(priority);
+        *p = p.max(priority);
+    }
+
+    /// Remembers the subtree placeholders of `node`, so their uris are loaded as subtrees.
+    pub fn register_subtrees(&mut self, node: &Node) {
+        if let (Some(subtree), Some(content)) = (&node.subtree, &node.content) {
+            self.subtrees.insert(content.uri.clone(), subtree.clone());
+        }
+        for child in node.children.iter() {
+            self.register_subtrees(child);
+        }
+    }
+
+    pub fn in_flight(
+        &self,
+        cache: &std::collections::HashMap<String, Tile>,
+        node_promises: &Vec<NodeRequest>,
+        overlays: &[DrapeLayer],
+    ) -> usize {
+        let contents = cache
+            .values()
+            .filter(|t| match &t.content {
+                TileContentState::Loading { promise, .. } => promise.ready().is_none(),
+                _ => false,
+            })
+            .count();
+        let nodes = node_promises
+            .iter()
+            .filter(|r| r.promise.ready().is_none())
+            .count();
+        let cancelled = self.cancelled.iter().filter(|c| !c.is_finished()).count();
+        contents + nodes + cancelled + TileCache::overlays_in_flight(overlays)
+    }
+
+    /// Whether `request` may be started now, failed requests wait for their retry time.
+    fn is_due(
+        &self,
+        request: &TileRequest,
+        cache: &std::collections::HashMap<String, Tile>,
+        overlays: &[DrapeLayer],
+        now: f64,
+    ) -> bool {
+        match request {
+            TileRequest::Content(id) => match cache.get(id).map(|t| &t.content) {
+                Some(TileContentState::None) => true,
+                Some(TileContentState::Failed { retry_at, .. }) => *retry_at <= now,
+                _ => false,
+            },
+            TileRequest::Node { uri, .. } => self
+                .node_failures
+                .get(uri)
+                .map(|f| f.retry_at <= now)
+                .unwrap_or(true),
+            TileRequest::Overlay { layer, tile } => {
+                match overlays.iter().find(|o| o.id() == *layer).map(|o| &o.source) {
+                    Some(DrapeSource::Tiles(raster)) => raster.is_due(*tile, now),
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    /// Starts the highest priority requests until `max_requests` are in flight and drops the rest.
+    pub fn dispatch(
+        &mut self,
+        cache: &mut std::collections::HashMap<String, Tile>,
+        node_promises: &mut Vec<NodeRequest>,
+        overlays: &mut [DrapeLayer],
+        source: &Arc<dyn TileSource>,
+        counters: &Arc<TileLoadCounters>,
+        target: Option<CompressedFormat>,
+        max_requests: usize,
+    ) {
+        let now = crate::http::now();
+        crate::http::reserve_workers(max_requests);
+        self.cancelled.retain(|c| !c.is_finished());
+        let mut in_flight = self.in_flight(cache, node_promises, overlays);
+        let mut queue: Vec<_> = std::mem::take(&mut self.queue)
+            .into_iter()
+            .filter(|(r, _)| self.is_due(r, cache, overlays, now))
+            .collect();
+        queue.sort_by(|a, b| b.1.total_cmp(&a.1));
+        self.waiting = queue.len().saturating_sub(max_requests.saturating_sub(in_flight));
+
+        for (request, _) in queue {
+            if in_flight >= max_requests {
+                break;
+            }
+            match request {
+                TileRequest::Content(id) => {
+                    if let Some(t) = cache.get_mut(&id) {
+                        let attempts = match &t.content {
+                            TileContentState::Failed { attempts, .. } => *attempts,
+                            _ => 0,
+                        };
+                        let cancel = crate::http::CancelToken::default();
+                        let promise =
+                            get_contents(id.clone(), source, cancel.clone(), counters, target);
+                        t.content = TileContentState::Loading {
+                            promise,
+                            cancel,
+                            attempts,
+                        };
+                        in_flight += 1;
+                    }
+                }
+                TileRequest::Node { parent, uri } => {
+                    if let Some(t) = cache.get_mut(&parent) {
+                        if let Some(i) = t.child_options.iter().position(|c| c == &uri) {
+                            t.child_options.remove(i);
+                            let request = match self.subtrees.get(&uri) {
+                                Some(subtree) => {
+                                    get_subtree(uri, parent.clone(), subtree.clone(), source)
+                                }
+                                None => get_node(uri, parent.clone(), source),
+                            };
+                            node_promises.push(request);
+                            in_flight += 1;
+                        }
+                    }
+                }
+                TileRequest::Overlay { layer, tile } => {
+                    if let Some(DrapeSource::Tiles(raster)) = overlays
+                        .iter_mut()
+                        .find(|o| o.id() == layer)
+                        .map(|o| &mut o.source)
+                    {
+                        raster.start(tile, counters);
+                        in_flight += 1;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use super::*;
+
+/// Result of one traversal of the tile tree, see [`select_tiles`].
+#[derive(Default, Debug, Clone)]
+pub struct TileSelection {
+    /// Tiles whose content is drawn, in traversal order.
+    pub selected: Vec<String>,
+    /// Visible tiles that were traversed.
+    pub visible: Vec<String>,
+    /// Visible tiles whose own level is shown, their bounding boxes are drawn on request.
+    pub bounding_boxes: Vec<String>,
+    /// Content and external tileset requests with their load priority.
+    pub requests: Vec<(TileRequest, f64)>,
+    /// Screen space error in pixels of every visible tile.
+    pub screen_space_errors: std::collections::HashMap<String, f64>,
+}
+
+/// Selects the tiles to draw for `s` without touching the GPU or the tiles.
+///
+/// Tiles that meet the screen space error are drawn, the others are refined into their
+/// children. With REPLACE refinement a tile stays drawn until all its visible children are
+/// ready, with ADD it is always drawn below them. Tiles without ready content are requested.
+pub fn select_tiles(
+    roots: &[String],
+    cache: &std::collections::HashMap<String, Tile>,
+    s: &ViewState,
+    max_level: usize,
+) -> TileSelection {
+    let mut selection = TileSelection::default();
+    for r in roots {
+        select_tile(r, cache, s, max_level, &mut selection);
+    }
+    selection
+}
+
+/// Returns whether the tile is visible and whether it or its children are drawn.
+fn select_tile(
+    id: &String,
+    cache: &std::collections::HashMap<String, Tile>,
+    s: &ViewState,
+    max_level: usize,
+    selection: &mut TileSelection,
+) -> (bool, bool) {
+    let Some(t) = cache.get(id) else {
+        return (false, false);
+    };
+    let is_visible = t.bv.is_visible(s.position) && t.bv.intersects_frustum(&s.frustum);
+    let mut has_rendered = false;
+
+    if is_visible {
+        selection.visible.push(id.clone());
+        let sse = s.screen_space_error(t);
+        selection.screen_space_errors.insert(id.clone(), sse);
+        let meet_sse = sse < s.maximum_screen_space_error;
+
+        let refine_children = !t.children.is_empty() && !meet_sse && max_level > 0;
+        if !refine_children || t.refine == Refine::Add {
+            let priority = s.load_priority(t);
+            if let TileContentState::None | TileContentState::Failed { .. } = &t.content {
+                selection
+                    .requests
+                    .push((TileRequest::Content(id.clone()), priority));
+            }
+            if !meet_sse && max_level > 0 {
+                for c in t.child_options.iter() {
+                    let request = TileRequest::Node {
+                        parent: id.clone(),
+                        uri: c.clone(),
+                    };
+                    selection.requests.push((request, priority));
+                }
+            }
+            if t.has_ready_content() {
+                selection.selected.push(id.clone());
+                has_rendered = true;
+            }
+            selection.bounding_boxes.push(id.clone());
+        }
+
+        if refine_children {
+            let mut children_rendered = true;
+            for c in t.children.iter() {
+                let (child_visible, child_rendered) =
+                    select_tile(c, cache, s, max_level - 1, selection);
+                if child_visible && !child_rendered {
+                    children_rendered = false;
+                }
+            }
+            // with ADD the parent content is drawn anyway and stays the fallback for its children
+            if t.refine == Refine::Replace {
+                has_rendered = children_rendered;
+            }
+        }
+    }
+
+    if !has_rendered && t.has_ready_content() {
+        selection.selected.push(id.clone());
+        has_rendered = true;
+    }
+
+    return (is_visible, has_rendered);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADIUS: f64 = 6_378_137.;
+
+    /// Box of 1 km on the equator at longitude 0, shifted by `y` along the y axis.
+    fn bounding(y: f64, half_width: f64) -> BoundingVolume {
+        BoundingVolume {
+            center: glam::dvec3(RADIUS, y, 0.),
+            x_axis: glam::dvec3(500., 0., 0.),
+            y_axis: glam::dvec3(0., half_width, 0.),
+            z_axis: glam::dvec3(0., 0., 500.),
+            region: None,
+        }
+    }
+
+    fn node(uri: &str, bounding: BoundingVolume, err: f64, children: Vec<Node>) -> Node {
+        Node {
+            bounding,
+            err,
+            content: Some(Content { uri: uri.into() }),
+            children,
+            ..Default::default()
+        }
+    }
+
+    /// Root with the two halves `a` and `b` as children and `external.json` below `a`.
+    fn tree(refine: Refine) -> (Vec<String>, std::collections::HashMap<String, Tile>) {
+        let mut root = node(
+            "root.glb",
+            bounding(0., 500.),
+            100.,
+            vec![
+                node(
+                    "a.glb",
+                    bounding(250., 250.),
+                    10.,
+                    vec![node("external.json", bounding(250., 250.), 10., vec![])],
+                ),
+                node("b.glb", bounding(-250., 250.), 10., vec![]),
+            ],
+        );
+        root.refine = Some(refine);
+        let source: Arc<dyn TileSource> = Arc::new(TilesetSource::new("test"));
+        let mut cache = Default::default();
+        let mut roots = vec![];
+        Tile::fill(
+            &root,
+            &source,
+            None,
+            refine,
+            DMat4::IDENTITY,
+            &mut cache,
+            &mut roots,
+            true,
+            None,
+        );
+        (roots, cache)
+    }
+
+    /// Camera `height` meters above the top of the root, looking down on it.
+    fn view_state(height: f64) -> ViewState {
+        let position = glam::dvec3(RADIUS + 500. + height, 0., 0.);
+        let direction = glam::dvec3(-1., 0., 0.);
+        ViewState::new(
+            position,
+            direction,
+            DMat4::look_to_rh(position, direction, glam::DVec3::Z),
+            DMat4::perspective_rh_gl(60f64.to_radians(), 1., 1., 1e8),
+            glam::dvec2(1000., 1000.),
+        )
+    }
+
+    fn set_ready(cache: &mut std::collections::HashMap<String, Tile>, ids: &[&str]) {
+        for id in ids {
+            cache.get_mut(*id).unwrap().content = TileContentState::Ready(vec![]);
+        }
+    }
+
+    fn content_requests(selection: &TileSelection) -> Vec<&str> {
+        let mut ids: Vec<_> = selection
+            .requests
+            .iter()
+            .filter_map(|(r, _)| match r {
+                TileRequest::Content(id) => Some(id.as_str()),
+                _ => None,
+            })
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn fill_splits_contents_and_external_tilesets() {
+        let (roots, cache) = tree(Refine::Replace);
+        assert_eq!(roots, ["root.glb"]);
+        assert_eq!(cache["root.glb"].children, ["a.glb", "b.glb"]);
+        assert_eq!(cache["a.glb"].child_options, ["external.json"]);
+        assert_eq!(cache["a.glb"].parent.as_deref(), Some("root.glb"));
+    }
+
+    #[test]
+    fn distant_root_meets_the_error() {
+        let (roots, mut cache) = tree(Refine::Replace);
+        let s = view_state(1_000_000.);
+        let selection = select_tiles(&roots, &cache, &s, 30);
+        assert!(selection.screen_space_errors["root.glb"] < s.maximum_screen_space_error);
+        assert_eq!(content_requests(&selection), ["root.glb"]);
+        assert!(selection.selected.is_empty());
+
+        set_ready(&mut cache, &["root.glb", "a.glb", "b.glb"]);
+        let selection = select_tiles(&roots, &cache, &s, 30);
+        assert_eq!(selection.selected, ["root.glb"]);
+        assert!(selection.requests.is_empty());
+    }
+
+    #[test]
+    fn replace_keeps_the_parent_until_the_children_are_ready() {
+        let (roots, mut cache) = tree(Refine::Replace);
+        let s = view_state(2000.);
+        set_ready(&mut cache, &["root.glb"]);
+        let selection = select_tiles(&roots, &cache, &s, 30);
+        assert!(selection.screen_space_errors["root.glb"] > s.maximum_screen_space_error);
+        assert_eq!(content_requests(&selection), ["a.glb", "b.glb"]);
+        assert_eq!(selection.selected, ["root.glb"]);
+
+        set_ready(&mut cache, &["a.glb"]);
+        let selection = select_tiles(&roots, &cache, &s, 30);
+        assert_eq!(selection.selected, ["a.glb", "root.glb"]);
+
+        set_ready(&mut cache, &["b.glb"]);
+        let selection = select_tiles(&roots, &cache, &s, 30);
+        assert_eq!(selection.selected, ["a.glb", "b.glb"]);
+    }
+
+    #[test]
+    fn add_draws_the_parent_below_its_children() {
+        let (roots, mut cache) = tree(Refine::Add);
+        set_ready(&mut cache, &["root.glb", "a.glb", "b.glb"]);
+        let selection = select_tiles(&roots, &cache, &view_state(2000.), 30);
+        assert_eq!(selection.selected, ["root.glb", "a.glb", "b.glb"]);
+    }
+
+    #[test]
+    fn max_level_stops_the_refinement() {
+        let (roots, mut cache) = tree(Refine::Replace);
+        set_ready(&mut cache, &["root.glb", "a.glb", "b.glb"]);
+        let selection = select_tiles(&roots, &cache, &view_state(2000.), 0);
+        assert_eq!(selection.selected, ["root.glb"]);
+        assert!(selection.requests.is_empty());
+    }
+
+    #[test]
+    fn close_leaf_requests_its_external_tileset() {
+        let (roots, mut cache) = tree(Refine::Replace);
+        set_ready(&mut cache, &["root.glb", "a.glb", "b.glb"]);
+        let selection = select_tiles(&roots, &cache, &view_state(10.), 30);
+        let external = TileRequest::Node {
+            parent: "a.glb".into(),
+            uri: "external.json".into(),
+        };
+        assert!(selection.requests.iter().any(|(r, _)| *r == external));
+    }
+
+    #[test]
+    fn tiles_behind_the_camera_are_culled() {
+        let (roots, cache) = tree(Refine::Replace);
+        let position = glam::dvec3(RADIUS + 2500., 0., 0.);
+        let direction = glam::dvec3(1., 0., 0.);
+        let s = ViewState::new(
+            position,
+            direction,
+            DMat4::look_to_rh(position, direction, glam::DVec3::Z),
+            DMat4::perspective_rh_gl(60f64.to_radians(), 1., 1., 1e8),
+            glam::dvec2(1000., 1000.),
+        );
+        let selection = select_tiles(&roots, &cache, &s, 30);
+        assert!(selection.visible.is_empty());
+        assert!(selection.requests.is_empty());
+    }
+}
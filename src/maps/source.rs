@@ -0,0 +1,195 @@
+use super::*;
+use std::sync::Arc;
+use crate::http::BoxFuture;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TileSourceError {
+    /// The api key was rejected, retrying will not help.
+    InvalidKey(String),
+    /// The server answered with a non-success status code.
+    Http { status: u16, message: String },
+    /// Offline mode and the request is not cached.
+    Offline(String),
+    Other(String),
+}
+
+impl std::fmt::Display for TileSourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidKey(message) => write!(f, "invalid api key: {message}"),
+            Self::Http { status, message } => write!(f, "{status}: {message}"),
+            Self::Offline(uri) => write!(f, "offline and not cached: {uri}"),
+            Self::Other(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl From<String> for TileSourceError {
+    fn from(value: String) -> Self {
+        Self::Other(value)
+    }
+}
+
+impl From<TileSourceError> for String {
+    fn from(value: TileSourceError) -> Self {
+        value.to_string()
+    }
+}
+
+/// Backend the [`TileCache`] streams tileset nodes and tile contents from.
+pub trait TileSource: Send + Sync {
+    /// Fetches the root node of the tileset.
+    fn get_root(&self) -> BoxFuture<'_, Result<Node, TileSourceError>>;
+
+    /// Downloads the raw bytes behind a (resolved) uri.
+    fn download<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Result<Vec<u8>, TileSourceError>>;
+
+    /// Resolves a content uri found in the document that was loaded from `base`.
+    fn resolve_uri(&self, base: &str, uri: &str) -> String;
+
+    /// Whether the content `uri` of a node is drawable tile content rather than an
+    /// external tileset, glb by default.
+    fn is_content(&self, uri: &str) -> bool {
+        uri.contains(".glb")
+    }
+
+    /// Decodes the downloaded content behind `uri`, glb by default. Basis textures are
+    /// transcoded to `target`, see [`compressed_target`].
+    fn decode_content<'a>(
+        &'a self,
+        uri: &'a str,
+        bytes: Vec<u8>,
+        target: Option<CompressedFormat>,
+    ) -> BoxFuture<'a, Result<Vec<TileContent>, String>> {
+        Box::pin(decode_glb(uri, bytes, target))
+    }
+
+    /// Fetches an external tileset json referenced by a tile content uri.
+    fn get_node<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Result<Node, TileSourceError>> {
+        Box::pin(async move {
+            let bytes = self.download(uri).await?;
+            let mut node = parse_tileset(&bytes)?;
+            expand_implicit(&mut node, uri, self).await?;
+            node.resolve_uris(uri, self);
+            Ok(node)
+        })
+    }
+}
+
+pub fn parse_tileset(bytes: &[u8]) -> Result<Node, TileSourceError> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Tileset {
+        root: Node,
+    }
+    let res: Tileset = serde_json::from_slice(bytes).map_err(|x| format!("err: {x}"))?;
+    Ok(res.root)
+}
+
+impl Node {
+    /// Rewrites all content uris of this subtree so they no longer depend on `base`.
+    pub fn resolve_uris<S: TileSource + ?Sized>(&mut self, base: &str, source: &S) {
+        if let Some(content) = &mut self.content {
+            content.uri = source.resolve_uri(base, &content.uri);
+        }
+        for child in self.children.iter_mut() {
+            child.resolve_uris(base, source);
+        }
+    }
+}
+
+/// Standard 3D Tiles `tileset.json`, either served over http(s) or read from a local directory.
+pub struct TilesetSource {
+    pub url: String,
+    pub disk_cache: Option<Arc<DiskCache>>,
+}
+
+impl TilesetSource {
+    /// `url` may point to a `tileset.json` or to the directory containing it.
+    pub fn new(url: impl Into<String>) -> Self {
+        let mut url: String = url.into();
+        let path = url.split('?').next().unwrap_or_default();
+        if !path.ends_with(".json") {
+            if !url.ends_with('/') {
+                url.push('/');
+            }
+            url.push_str("tileset.json");
+        }
+        Self {
+            url,
+            disk_cache: None,
+        }
+    }
+
+    /// Caches remote requests, local tilesets are always read directly.
+    pub fn with_disk_cache(mut self, disk_cache: Arc<DiskCache>) -> Self {
+        self.disk_cache = Some(disk_cache);
+        self
+    }
+}
+
+pub(crate) fn is_remote(uri: &str) -> bool {
+    uri.starts_with("http://") || uri.starts_with("https://")
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|x| format!("{path}: {x}"))
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    Err(format!("{path}: local tilesets are not supported on the web"))
+}
+
+impl TileSource for TilesetSource {
+    fn get_root(&self) -> BoxFuture<'_, Result<Node, TileSourceError>> {
+        self.get_node(&self.url)
+    }
+
+    fn download<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Result<Vec<u8>, TileSourceError>> {
+        Box::pin(download_file_or_url(uri, self.disk_cache.as_deref()))
+    }
+
+    fn resolve_uri(&self, base: &str, uri: &str) -> String {
+        resolve_file_or_url(base, uri)
+    }
+}
+
+/// Reads a local file or fetches a url through `disk_cache`.
+pub(crate) async fn download_file_or_url(
+    uri: &str,
+    disk_cache: Option<&DiskCache>,
+) -> Result<Vec<u8>, TileSourceError> {
+    download_file_or_url_with_headers(uri, disk_cache, &[]).await
+}
+
+/// [`download_file_or_url`] sending `headers` with remote requests.
+pub(crate) async fn download_file_or_url_with_headers(
+    uri: &str,
+    disk_cache: Option<&DiskCache>,
+    headers: &[(&str, &str)],
+) -> Result<Vec<u8>, TileSourceError> {
+    if !is_remote(uri) {
+        return Ok(read_file(uri)?);
+    }
+    let url = reqwest::Url::parse(uri).map_err(|x| format!("{uri}: {x}"))?;
+    fetch_cached_with_headers(disk_cache, url, headers).await
+}
+
+/// Resolves `uri` relative to the url or file path `base`.
+pub(crate) fn resolve_file_or_url(base: &str, uri: &str) -> String {
+    if is_remote(uri) {
+        return uri.to_string();
+    }
+    if is_remote(base) {
+        return reqwest::Url::parse(base)
+            .and_then(|b| b.join(uri))
+            .map(|u| u.to_string())
+            .unwrap_or_else(|_| uri.to_string());
+    }
+    let path = uri.split('?').next().unwrap_or_default();
+    match std::path::Path::new(base).parent() {
+        Some(dir) => dir.join(path).to_string_lossy().to_string(),
+        None => path.to_string(),
+    }
+}
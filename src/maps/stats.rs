@@ -0,0 +1,115 @@
+use super::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters shared with the loading tasks.
+#[derive(Default, Debug)]
+pub struct TileLoadCounters {
+    /// Bytes of tile contents fetched, including disk cache hits.
+    pub bytes_downloaded: AtomicU64,
+    /// Number of decoded tile contents.
+    pub decoded: AtomicU64,
+    /// Sum of the decode times in microseconds.
+    pub decode_micros: AtomicU64,
+}
+
+impl TileLoadCounters {
+    pub fn add_download(&self, bytes: usize) {
+        self.bytes_downloaded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_decode(&self, seconds: f64) {
+        self.decoded.fetch_add(1, Ordering::Relaxed);
+        self.decode_micros
+            .fetch_add((seconds * 1_000_000.) as u64, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of the state of a [`TileCache`], see [`TileCache::stats`].
+#[derive(Default, Debug, Clone)]
+pub struct TileCacheStats {
+    /// Tiles known from the loaded parts of the tileset.
+    pub tiles: usize,
+    /// Tiles traversed as visible in the last frame.
+    pub visible: usize,
+    /// Tiles drawn in the last frame.
+    pub selected: usize,
+    /// Contents and external tilesets being downloaded or decoded.
+    pub loading: usize,
+    /// Decoded contents waiting for the upload to the GPU.
+    pub waiting_for_upload: usize,
+    /// Tiles with content on the GPU.
+    pub ready: usize,
+    /// Tiles and external tilesets that failed to load.
+    pub failed: usize,
+    /// Requests that could not be started in the last frame.
+    pub queue_depth: usize,
+    pub bytes_downloaded: u64,
+    pub cpu_bytes: usize,
+    pub gpu_bytes: usize,
+    /// Average decode time of a tile content in seconds.
+    pub average_decode_time: f64,
+}
+
+/// Loading progress reported to the receivers of [`TileCache::subscribe`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum TileEvent {
+    /// The content of the tile `id` was uploaded and is drawn from now on.
+    Loaded { id: String, gpu_bytes: usize },
+    /// Loading the content of the tile `id` failed, it is retried after a delay.
+    Failed {
+        id: String,
+        error: String,
+        attempts: u32,
+    },
+    /// The external tileset or subtree `uri` was added below the tile `parent`.
+    NodeLoaded { parent: String, uri: String },
+    NodeFailed { uri: String, error: String },
+    /// The content of the tile `id` was released to stay within the memory budgets.
+    Evicted { id: String },
+}
+
+/// Sends `event` to all `subscribers`, receivers that were dropped are removed.
+pub(crate) fn emit(subscribers: &mut Vec<std::sync::mpsc::Sender<TileEvent>>, event: TileEvent) {
+    if subscribers.is_empty() {
+        return;
+    }
+    subscribers.retain(|s| s.send(event.clone()).is_ok());
+}
+
+impl TileCache {
+    /// Receives a [`TileEvent`] for every tile that is loaded, fails or is evicted.
+    pub fn subscribe(&mut self) -> std::sync::mpsc::Receiver<TileEvent> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub fn stats(&self) -> TileCacheStats {
+        let mut stats = TileCacheStats {
+            tiles: self.cache.len(),
+            visible: self.selection.visible.len(),
+            selected: self.selection.selected.len(),
+            loading: self.scheduler.in_flight(&self.cache, &self.node_promises, &self.overlays),
+            failed: self.scheduler.node_failures.len(),
+            queue_depth: self.scheduler.waiting,
+            bytes_downloaded: self.counters.bytes_downloaded.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        for t in self.cache.values() {
+            match &t.content {
+                TileContentState::Decoded(_) => stats.waiting_for_upload += 1,
+                TileContentState::Ready(_) => stats.ready += 1,
+                TileContentState::Failed { .. } => stats.failed += 1,
+                TileContentState::None | TileContentState::Loading { .. } => {}
+            }
+        }
+        (stats.cpu_bytes, stats.gpu_bytes) = self.memory_usage();
+        let decoded = self.counters.decoded.load(Ordering::Relaxed);
+        if decoded > 0 {
+            let micros = self.counters.decode_micros.load(Ordering::Relaxed);
+            stats.average_decode_time = micros as f64 / decoded as f64 / 1_000_000.;
+        }
+        stats
+    }
+}
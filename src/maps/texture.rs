@@ -0,0 +1,767 @@
+use super::*;
+
+const BASISU_EXTENSION: &str = "KHR_texture_basisu";
+const KTX2_MAGIC: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+
+/// Decoded image of a texture.
+#[derive(Clone)]
+pub enum TileImage {
+    /// Uncompressed pixels, uploaded by three-d.
+    Pixels(three_d::CpuTexture),
+    /// Basis Universal image transcoded to a format the GPU samples directly.
+    Compressed(CompressedTexture),
+}
+
+/// Block compressed formats basis images are transcoded to, all with 16 byte 4x4 blocks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompressedFormat {
+    Astc4x4,
+    Bc7,
+    Etc2Rgba,
+    Bc3,
+}
+
+impl CompressedFormat {
+    /// In order of preference.
+    const ALL: [CompressedFormat; 4] = [
+        CompressedFormat::Astc4x4,
+        CompressedFormat::Bc7,
+        CompressedFormat::Etc2Rgba,
+        CompressedFormat::Bc3,
+    ];
+
+    /// GL internal format. The texels are sampled as they are stored, see [`TileMaterial`].
+    pub fn gl_format(&self) -> u32 {
+        match self {
+            CompressedFormat::Astc4x4 => 0x93B0,
+            CompressedFormat::Bc7 => 0x8E8C,
+            CompressedFormat::Etc2Rgba => 0x9278,
+            CompressedFormat::Bc3 => 0x83F3,
+        }
+    }
+
+    /// GL and WebGL extensions that expose the format.
+    fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            CompressedFormat::Astc4x4 => &[
+                "GL_KHR_texture_compression_astc_ldr",
+                "WEBGL_compressed_texture_astc",
+            ],
+            CompressedFormat::Bc7 => &[
+                "GL_ARB_texture_compression_bptc",
+                "GL_EXT_texture_compression_bptc",
+                "EXT_texture_compression_bptc",
+            ],
+            CompressedFormat::Etc2Rgba => &[
+                "GL_OES_compressed_ETC2_RGBA8_texture",
+                "WEBGL_compressed_texture_etc",
+            ],
+            CompressedFormat::Bc3 => &[
+                "GL_EXT_texture_compression_s3tc",
+                "WEBGL_compressed_texture_s3tc",
+            ],
+        }
+    }
+}
+
+/// Format basis images are transcoded to for `ctx3d`, the preferred one it supports or
+/// `None` for rgba. Each [`TileCache`] asks its own context, since the decoding runs off
+/// the GL thread the format is passed along with the requests.
+pub fn compressed_target(ctx3d: &three_d::Context) -> Option<CompressedFormat> {
+    let extensions = ctx3d.supported_extensions();
+    CompressedFormat::ALL
+        .into_iter()
+        .find(|f| f.extensions().iter().any(|e| extensions.contains(*e)))
+}
+
+/// Transcoded basis image with its mip levels, the largest first.
+#[derive(Clone)]
+pub struct CompressedTexture {
+    pub width: u32,
+    pub height: u32,
+    pub format: CompressedFormat,
+    pub levels: Vec<Vec<u8>>,
+}
+
+impl CompressedTexture {
+    pub fn byte_size(&self) -> usize {
+        self.levels.iter().map(|l| l.len()).sum()
+    }
+}
+
+/// [`CompressedTexture`] on the GPU. three-d has no compressed formats, so the texture is
+/// created with GL directly and bound by [`TileMaterial`].
+pub struct CompressedTexture2D {
+    context: three_d::Context,
+    id: three_d::context::Texture,
+}
+
+impl CompressedTexture2D {
+    pub fn new(
+        ctx3d: &three_d::Context,
+        texture: &CompressedTexture,
+        quality: TextureQuality,
+    ) -> Result<Self, String> {
+        use three_d::context as gl;
+        let mipmaps = quality != TextureQuality::Low && texture.levels.len() > 1;
+        let levels = if mipmaps { texture.levels.len() } else { 1 };
+        unsafe {
+            let id = ctx3d.create_texture()?;
+            ctx3d.bind_texture(gl::TEXTURE_2D, Some(id));
+            for (level, data) in texture.levels.iter().take(levels).enumerate() {
+                let (w, h) = level_size(texture.width, texture.height, level);
+                ctx3d.compressed_tex_image_2d(
+                    gl::TEXTURE_2D,
+                    level as i32,
+                    texture.format.gl_format() as i32,
+                    w as i32,
+                    h as i32,
+                    0,
+                    data.len() as i32,
+                    data,
+                );
+            }
+            let min_filter = if mipmaps { gl::LINEAR_MIPMAP_LINEAR } else { gl::LINEAR };
+            ctx3d.tex_parameter_i32(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, levels as i32 - 1);
+            ctx3d.tex_parameter_i32(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, min_filter as i32);
+            ctx3d.tex_parameter_i32(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+            ctx3d.tex_parameter_i32(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+            ctx3d.tex_parameter_i32(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+            ctx3d.bind_texture(gl::TEXTURE_2D, None);
+            Ok(Self {
+                context: ctx3d.clone(),
+                id,
+            })
+        }
+    }
+
+    /// Binds this texture to the sampler `name` of `program` on the texture `unit`, which
+    /// must not be one three-d hands out for the other textures of `program`.
+    pub fn use_texture(&self, program: &three_d::Program, name: &str, unit: u32) {
+        unsafe {
+            self.context.active_texture(three_d::context::TEXTURE0 + unit);
+            self.context
+                .bind_texture(three_d::context::TEXTURE_2D, Some(self.id));
+        }
+        program.use_uniform(name, unit as i32);
+    }
+}
+
+impl Drop for CompressedTexture2D {
+    fn drop(&mut self) {
+        unsafe {
+            self.context.delete_texture(self.id);
+        }
+    }
+}
+
+/// Texture of an uploaded [`TileContent`].
+pub enum TileTextureGPU {
+    Pixels(three_d::Texture2DRef),
+    Compressed(CompressedTexture2D),
+}
+
+impl TileTextureGPU {
+    /// Uploads `image`, the filtering of [`TileImage::Pixels`] is already applied.
+    pub fn new(
+        ctx3d: &three_d::Context,
+        image: &TileImage,
+        quality: TextureQuality,
+    ) -> Result<Self, String> {
+        match image {
+            TileImage::Pixels(texture) => Ok(TileTextureGPU::Pixels(
+                three_d::Texture2DRef::from_cpu_texture(ctx3d, texture),
+            )),
+            TileImage::Compressed(texture) => Ok(TileTextureGPU::Compressed(
+                CompressedTexture2D::new(ctx3d, texture, quality)?,
+            )),
+        }
+    }
+}
+
+/// Decodes all images of `doc`, an image that cannot be decoded keeps its error. Basis
+/// images are transcoded to `target`, see [`compressed_target`].
+pub fn load_images(
+    doc: &gltf::Document,
+    buffer_data: &[gltf::buffer::Data],
+    target: Option<CompressedFormat>,
+) -> Vec<Result<TileImage, String>> {
+    doc.images()
+        .map(|image| load_image(&image, buffer_data, target))
+        .collect()
+}
+
+fn load_image(
+    image: &gltf::Image<'_>,
+    buffer_data: &[gltf::buffer::Data],
+    target: Option<CompressedFormat>,
+) -> Result<TileImage, String> {
+    if let gltf::image::Source::View { view, .. } = image.source() {
+        let bytes = buffer_data
+            .get(view.buffer().index())
+            .and_then(|b| b.get(view.offset()..view.offset() + view.length()))
+            .ok_or_else(|| {
+                format!("image {}: buffer view {} out of range", image.index(), view.index())
+            })?;
+        if bytes.starts_with(&KTX2_MAGIC) {
+            return decode_ktx2(bytes, target);
+        }
+    }
+    let data = gltf::image::Data::from_source(image.source(), None, buffer_data)
+        .map_err(|x| format!("image {}: {x}", image.index()))?;
+    Ok(TileImage::Pixels(cpu_texture(&data)))
+}
+
+/// Image of `texture`, the KTX2 image of `KHR_texture_basisu` is preferred over the fallback.
+/// Textures with only a basis image are accepted by gltf's `allow_empty_texture`.
+pub fn texture_image<'a>(
+    texture: &gltf::Texture<'_>,
+    images: &'a [Result<TileImage, String>],
+) -> Result<&'a TileImage, String> {
+    let basisu = texture
+        .extension_value(BASISU_EXTENSION)
+        .and_then(|x| x.get("source")?.as_u64())
+        .and_then(|i| images.get(i as usize));
+    let basisu_error = match basisu {
+        Some(Ok(image)) => return Ok(image),
+        Some(Err(error)) => Some(error),
+        None => None,
+    };
+    let Some(source) = texture.source() else {
+        return Err(basisu_error
+            .cloned()
+            .unwrap_or(format!("texture {} without image", texture.index())));
+    };
+    let index = source.index();
+    match images.get(index) {
+        Some(Ok(image)) => Ok(image),
+        Some(Err(error)) => Err(error.clone()),
+        None => Err(format!("missing image {index}")),
+    }
+}
+
+fn texture(width: u32, height: u32, data: three_d::TextureData) -> three_d::CpuTexture {
+    let mut texture = three_d::CpuTexture::default();
+    texture.width = width;
+    texture.height = height;
+    texture.wrap_s = three_d::Wrapping::ClampToEdge;
+    texture.wrap_t = three_d::Wrapping::ClampToEdge;
+    texture.data = data;
+    return texture;
+}
+
+/// Decodes a png, jpeg or webp image, e.g. a raster map tile.
+pub fn decode_image(bytes: &[u8]) -> Result<three_d::CpuTexture, String> {
+    let image = image::load_from_memory(bytes)
+        .map_err(|x| format!("image: {x}"))?
+        .to_rgba8();
+    let (width, height) = image.dimensions();
+    let data = image.pixels().map(|p| p.0).collect();
+    Ok(texture(width, height, three_d::TextureData::RgbaU8(data)))
+}
+
+/// Converts every [`gltf::image::Format`]. Grayscale images are expanded to rgb(a) so they
+/// stay gray when sampled as colour, 16 bit channels are reduced to 8 bit.
+pub fn cpu_texture(image: &gltf::image::Data) -> three_d::CpuTexture {
+    use gltf::image::Format;
+    use three_d::TextureData;
+
+    let p = &image.pixels;
+    let u16s = || -> Vec<u8> {
+        p.chunks_exact(2)
+            .map(|c| (u16::from_le_bytes([c[0], c[1]]) >> 8) as u8)
+            .collect()
+    };
+    let f32s = || -> Vec<f32> {
+        p.chunks_exact(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect()
+    };
+    let gray = |v: &[u8]| TextureData::RgbU8(v.iter().map(|&l| [l, l, l]).collect());
+    let gray_alpha =
+        |v: &[u8]| TextureData::RgbaU8(v.chunks_exact(2).map(|c| [c[0], c[0], c[0], c[1]]).collect());
+    let rgb = |v: &[u8]| TextureData::RgbU8(v.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect());
+    let rgba = |v: &[u8]| {
+        TextureData::RgbaU8(v.chunks_exact(4).map(|c| [c[0], c[1], c[2], c[3]]).collect())
+    };
+
+    let data = match image.format {
+        Format::R8 => gray(p),
+        Format::R8G8 => gray_alpha(p),
+        Format::R8G8B8 => rgb(p),
+        Format::R8G8B8A8 => rgba(p),
+        Format::R16 => gray(&u16s()),
+        Format::R16G16 => gray_alpha(&u16s()),
+        Format::R16G16B16 => rgb(&u16s()),
+        Format::R16G16B16A16 => rgba(&u16s()),
+        Format::R32G32B32FLOAT => {
+            TextureData::RgbF32(f32s().chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect())
+        }
+        Format::R32G32B32A32FLOAT => TextureData::RgbaF32(
+            f32s().chunks_exact(4).map(|c| [c[0], c[1], c[2], c[3]]).collect(),
+        ),
+    };
+    texture(image.width, image.height, data)
+}
+
+/// Decodes a KTX2 image. Uncompressed formats use their base level. Basis Universal images,
+/// UASTC and ETC1S (BasisLZ), are transcoded with all their levels to the block format
+/// `target`, or to rgba if it is `None`. The transcoder is native only, on the web basis
+/// images fail and the png/jpg fallback of the texture is used instead.
+pub fn decode_ktx2(bytes: &[u8], target: Option<CompressedFormat>) -> Result<TileImage, String> {
+    let reader = ktx2::Reader::new(bytes).map_err(|x| format!("ktx2: {x:?}"))?;
+    let header = reader.header();
+    let (width, height) = (header.pixel_width, header.pixel_height.max(1));
+    if header.supercompression_scheme == Some(ktx2::SupercompressionScheme::BasisLZ) {
+        let levels: Vec<&[u8]> = reader.levels().map(|l| l.data).collect();
+        let global = reader.supercompression_global_data();
+        return transcode_etc1s(global, &levels, width, height, target);
+    }
+    let inflate = |data: &[u8]| -> Result<Vec<u8>, String> {
+        match header.supercompression_scheme {
+            None => Ok(data.to_vec()),
+            Some(ktx2::SupercompressionScheme::Zstandard) => {
+                use std::io::Read;
+                let mut decoder = ruzstd::decoding::StreamingDecoder::new(data)
+                    .map_err(|x| format!("ktx2: {x}"))?;
+                let mut data = vec![];
+                decoder
+                    .read_to_end(&mut data)
+                    .map_err(|x| format!("ktx2: {x}"))?;
+                Ok(data)
+            }
+            Some(scheme) => Err(format!("ktx2: unsupported supercompression {scheme:?}")),
+        }
+    };
+
+    let level = reader.levels().next().ok_or("ktx2: no levels")?;
+    let texture_data = match header.format {
+        Some(ktx2::Format::R8G8B8A8_UNORM) | Some(ktx2::Format::R8G8B8A8_SRGB) => {
+            rgba(&inflate(level.data)?)
+        }
+        Some(ktx2::Format::R8G8B8_UNORM) | Some(ktx2::Format::R8G8B8_SRGB) => {
+            let data = inflate(level.data)?;
+            three_d::TextureData::RgbU8(data.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect())
+        }
+        Some(ktx2::Format::R8_UNORM) | Some(ktx2::Format::R8_SRGB) => {
+            let data = inflate(level.data)?;
+            three_d::TextureData::RgbU8(data.iter().map(|&l| [l, l, l]).collect())
+        }
+        // KHR_texture_basisu images without format that are not BasisLZ are UASTC
+        None => {
+            let levels = reader
+                .levels()
+                .map(|l| inflate(l.data))
+                .collect::<Result<Vec<_>, _>>()?;
+            return transcode_uastc(&levels, width, height, target);
+        }
+        Some(format) => return Err(format!("ktx2: unsupported format {format:?}")),
+    };
+    Ok(TileImage::Pixels(texture(width, height, texture_data)))
+}
+
+fn rgba(v: &[u8]) -> three_d::TextureData {
+    three_d::TextureData::RgbaU8(v.chunks_exact(4).map(|c| [c[0], c[1], c[2], c[3]]).collect())
+}
+
+/// Size of mip `level` of a `width` x `height` image.
+fn level_size(width: u32, height: u32, level: usize) -> (u32, u32) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
+
+/// Transcoded `levels` as [`TileImage::Compressed`] in `target`, or the base level as rgba.
+#[cfg(not(target_arch = "wasm32"))]
+fn transcoded_image(
+    target: Option<CompressedFormat>,
+    width: u32,
+    height: u32,
+    mut levels: Vec<Vec<u8>>,
+) -> TileImage {
+    match target {
+        Some(format) => TileImage::Compressed(CompressedTexture {
+            width,
+            height,
+            format,
+            levels,
+        }),
+        None => TileImage::Pixels(texture(width, height, rgba(&levels.swap_remove(0)))),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn init_transcoder() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(basis_universal::transcoder_init);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn transcode_uastc(
+    levels: &[Vec<u8>],
+    width: u32,
+    height: u32,
+    target: Option<CompressedFormat>,
+) -> Result<TileImage, String> {
+    use basis_universal::{
+        DecodeFlags, LowLevelUastcTranscoder, SliceParametersUastc, TranscoderBlockFormat,
+    };
+    init_transcoder();
+
+    let block_format = match target {
+        Some(CompressedFormat::Astc4x4) => TranscoderBlockFormat::ASTC_4x4,
+        Some(CompressedFormat::Bc7) => TranscoderBlockFormat::BC7,
+        Some(CompressedFormat::Etc2Rgba) => TranscoderBlockFormat::ETC2_RGBA,
+        Some(CompressedFormat::Bc3) => TranscoderBlockFormat::BC3,
+        None => TranscoderBlockFormat::RGBA32,
+    };
+    // three-d builds the mipmaps of rgba textures itself
+    let count = if target.is_some() { levels.len() } else { 1 };
+    let transcoder = LowLevelUastcTranscoder::new();
+    let mut transcoded = vec![];
+    for (level, blocks) in levels.iter().take(count).enumerate() {
+        let (w, h) = level_size(width, height, level);
+        let data = transcoder
+            .transcode_slice(
+                blocks,
+                SliceParametersUastc {
+                    num_blocks_x: w.div_ceil(4),
+                    num_blocks_y: h.div_ceil(4),
+                    has_alpha: true,
+                    original_width: w,
+                    original_height: h,
+                },
+                DecodeFlags::HIGH_QUALITY,
+                block_format,
+            )
+            .map_err(|x| format!("uastc: {x:?}"))?;
+        transcoded.push(data);
+    }
+    if transcoded.is_empty() {
+        return Err("uastc: no levels".into());
+    }
+    Ok(transcoded_image(target, width, height, transcoded))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn transcode_etc1s(
+    global: &[u8],
+    levels: &[&[u8]],
+    width: u32,
+    height: u32,
+    target: Option<CompressedFormat>,
+) -> Result<TileImage, String> {
+    use basis_universal::{
+        DecodeFlags, TranscodeParameters, Transcoder, TranscoderTextureFormat,
+    };
+    init_transcoder();
+
+    let basis = basis_file(global, levels, width, height)?;
+    let format = match target {
+        Some(CompressedFormat::Astc4x4) => TranscoderTextureFormat::ASTC_4x4_RGBA,
+        Some(CompressedFormat::Bc7) => TranscoderTextureFormat::BC7_RGBA,
+        Some(CompressedFormat::Etc2Rgba) => TranscoderTextureFormat::ETC2_RGBA,
+        Some(CompressedFormat::Bc3) => TranscoderTextureFormat::BC3_RGBA,
+        None => TranscoderTextureFormat::RGBA32,
+    };
+    let count = if target.is_some() { levels.len() } else { 1 };
+    let mut transcoder = Transcoder::new();
+    transcoder
+        .prepare_transcoding(&basis)
+        .map_err(|_| "etc1s: invalid basis data")?;
+    let mut transcoded = vec![];
+    for level in 0..count {
+        let data = transcoder
+            .transcode_image_level(
+                &basis,
+                format,
+                TranscodeParameters {
+                    image_index: 0,
+                    level_index: level as u32,
+                    decode_flags: Some(DecodeFlags::HIGH_QUALITY),
+                    output_row_pitch_in_blocks_or_pixels: None,
+                    output_rows_in_pixels: None,
+                },
+            )
+            .map_err(|x| format!("etc1s: {x:?}"))?;
+        transcoded.push(data);
+    }
+    transcoder.end_transcoding();
+    Ok(transcoded_image(target, width, height, transcoded))
+}
+
+/// Wraps the BasisLZ `global` data and `levels` of a KTX2 image in a .basis file, the
+/// container the transcoder reads ETC1S from. Codebooks and slices are stored the same way
+/// in both, only the headers differ. Array and cube map images are not supported.
+#[cfg(not(target_arch = "wasm32"))]
+fn basis_file(global: &[u8], levels: &[&[u8]], width: u32, height: u32) -> Result<Vec<u8>, String> {
+    const HEADER_SIZE: usize = 77;
+    const SLICE_DESC_SIZE: usize = 23;
+    const IMAGE_DESC_SIZE: usize = 20;
+    let truncated = || "basislz: truncated global data".to_string();
+    let u16_at = |o: usize| -> Result<usize, String> {
+        let b = global.get(o..o + 2).ok_or_else(truncated)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]) as usize)
+    };
+    let u32_at = |o: usize| -> Result<usize, String> {
+        let b = global.get(o..o + 4).ok_or_else(truncated)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+
+    let endpoint_count = u16_at(0)?;
+    let selector_count = u16_at(2)?;
+    let endpoints_size = u32_at(4)?;
+    let selectors_size = u32_at(8)?;
+    let tables_size = u32_at(12)?;
+    let codebooks_start = 20 + IMAGE_DESC_SIZE * levels.len();
+    let codebooks = global
+        .get(codebooks_start..codebooks_start + endpoints_size + selectors_size + tables_size)
+        .ok_or_else(truncated)?;
+
+    // (level, flags, data) of each slice, an alpha slice follows the rgb slice of its level
+    let mut slices = vec![];
+    let mut has_alpha = false;
+    for (level, data) in levels.iter().enumerate() {
+        let desc = 20 + IMAGE_DESC_SIZE * level;
+        let slice = |offset: usize, size: usize| {
+            data.get(offset..offset + size)
+                .ok_or(format!("basislz: slice outside of level {level}"))
+        };
+        slices.push((level, 0, slice(u32_at(desc + 4)?, u32_at(desc + 8)?)?));
+        let alpha_size = u32_at(desc + 16)?;
+        if alpha_size > 0 {
+            has_alpha = true;
+            slices.push((level, 1, slice(u32_at(desc + 12)?, alpha_size)?));
+        }
+    }
+
+    let endpoints_offset = HEADER_SIZE + SLICE_DESC_SIZE * slices.len();
+    let selectors_offset = endpoints_offset + endpoints_size;
+    let tables_offset = selectors_offset + selectors_size;
+    let put = |file: &mut Vec<u8>, value: usize, bytes: usize| {
+        file.extend_from_slice(&(value as u64).to_le_bytes()[..bytes]);
+    };
+
+    let mut file = vec![];
+    put(&mut file, 0x4273, 2); // "sB"
+    put(&mut file, 0x13, 2); // version
+    put(&mut file, HEADER_SIZE, 2);
+    put(&mut file, 0, 2); // header crc, set below
+    put(&mut file, 0, 4); // data size, set below
+    put(&mut file, 0, 2); // data crc, set below
+    put(&mut file, slices.len(), 3);
+    put(&mut file, 1, 3); // images
+    put(&mut file, 0, 1); // ETC1S
+    put(&mut file, if has_alpha { 1 | 4 } else { 1 }, 2); // ETC1S, has alpha slices
+    put(&mut file, 0, 1); // 2D
+    put(&mut file, 0, 3); // us per frame
+    put(&mut file, 0, 4); // reserved
+    put(&mut file, 0, 4); // userdata0
+    put(&mut file, 0, 4); // userdata1
+    put(&mut file, endpoint_count, 2);
+    put(&mut file, endpoints_offset, 4);
+    put(&mut file, endpoints_size, 3);
+    put(&mut file, selector_count, 2);
+    put(&mut file, selectors_offset, 4);
+    put(&mut file, selectors_size, 3);
+    put(&mut file, tables_offset, 4);
+    put(&mut file, tables_size, 4);
+    put(&mut file, HEADER_SIZE, 4); // slice descs
+    put(&mut file, 0, 4); // extended data
+    put(&mut file, 0, 4);
+    debug_assert_eq!(file.len(), HEADER_SIZE);
+
+    let mut offset = tables_offset + tables_size;
+    for (level, flags, data) in slices.iter() {
+        let (w, h) = level_size(width, height, *level);
+        put(&mut file, 0, 3); // image
+        put(&mut file, *level, 1);
+        put(&mut file, *flags, 1);
+        put(&mut file, w as usize, 2);
+        put(&mut file, h as usize, 2);
+        put(&mut file, w.div_ceil(4) as usize, 2);
+        put(&mut file, h.div_ceil(4) as usize, 2);
+        put(&mut file, offset, 4);
+        put(&mut file, data.len(), 4);
+        put(&mut file, crc16(data) as usize, 2);
+        offset += data.len();
+    }
+    file.extend_from_slice(codebooks);
+    for (_, _, data) in slices.iter() {
+        file.extend_from_slice(data);
+    }
+
+    let data_size = (file.len() - HEADER_SIZE) as u32;
+    file[8..12].copy_from_slice(&data_size.to_le_bytes());
+    let data_crc = crc16(&file[HEADER_SIZE..]);
+    file[12..14].copy_from_slice(&data_crc.to_le_bytes());
+    let header_crc = crc16(&file[8..HEADER_SIZE]);
+    file[6..8].copy_from_slice(&header_crc.to_le_bytes());
+    Ok(file)
+}
+
+/// CRC-16 of the .basis headers.
+#[cfg(not(target_arch = "wasm32"))]
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = !0u16;
+    for &b in data {
+        let q = b as u16 ^ (crc >> 8);
+        let k = (q >> 4) ^ q;
+        crc = (crc << 8) ^ k ^ (k << 5) ^ (k << 12);
+    }
+    !crc
+}
+
+#[cfg(target_arch = "wasm32")]
+fn transcode_uastc(
+    _levels: &[Vec<u8>],
+    _width: u32,
+    _height: u32,
+    _target: Option<CompressedFormat>,
+) -> Result<TileImage, String> {
+    Err("basis textures are not supported on the web".into())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn transcode_etc1s(
+    _global: &[u8],
+    _levels: &[&[u8]],
+    _width: u32,
+    _height: u32,
+    _target: Option<CompressedFormat>,
+) -> Result<TileImage, String> {
+    Err("basis textures are not supported on the web".into())
+}
+
+/// Filtering of tile textures.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TextureQuality {
+    /// Bilinear filtering without mipmaps.
+    Low,
+    /// Trilinear filtering of mipmaps.
+    Medium,
+    /// Mipmaps with 16x anisotropic filtering, keeps oblique views at street level sharp.
+    #[default]
+    High,
+}
+
+impl TextureQuality {
+    pub fn apply(&self, texture: &mut three_d::CpuTexture) {
+        texture.min_filter = three_d::Interpolation::Linear;
+        texture.mag_filter = three_d::Interpolation::Linear;
+        texture.mipmap = match self {
+            TextureQuality::Low => None,
+            TextureQuality::Medium => Some(three_d::Mipmap {
+                filter: three_d::Interpolation::Linear,
+                max_ratio: 1,
+                max_levels: 16,
+            }),
+            // three-d only sets the anisotropy if the driver supports it
+            TextureQuality::High => Some(three_d::Mipmap {
+                filter: three_d::Interpolation::Linear,
+                max_ratio: 16,
+                max_levels: 16,
+            }),
+        };
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    /// Little endian field of `bytes` bytes at `offset`.
+    fn field(file: &[u8], offset: usize, bytes: usize) -> usize {
+        let mut value = [0; 8];
+        value[..bytes].copy_from_slice(&file[offset..offset + bytes]);
+        u64::from_le_bytes(value) as usize
+    }
+
+    /// BasisLZ global data of two levels with rgb and alpha slices.
+    fn global_data() -> Vec<u8> {
+        let mut global = vec![];
+        for (value, bytes) in [(7, 2), (5, 2), (3, 4), (2, 4), (1, 4), (0, 4)] {
+            global.extend_from_slice(&(value as u64).to_le_bytes()[..bytes]);
+        }
+        // flags, rgb offset and length, alpha offset and length of each level
+        for desc in [[0u32, 0, 6, 6, 4], [0, 0, 2, 2, 1]] {
+            for value in desc {
+                global.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        // endpoints, selectors and tables
+        global.extend_from_slice(&[0xE0, 0xE1, 0xE2, 0x50, 0x51, 0x7A]);
+        global
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        // CRC-16/GENIBUS
+        assert_eq!(crc16(b"123456789"), 0xD64E);
+        assert_eq!(crc16(&[]), 0);
+    }
+
+    #[test]
+    fn basis_file_header() {
+        let level0: Vec<u8> = (1..=10).collect();
+        let level1 = [20u8, 21, 22];
+        let file = basis_file(&global_data(), &[&level0[..], &level1[..]], 8, 4).unwrap();
+
+        assert_eq!(field(&file, 0, 2), 0x4273);
+        assert_eq!(field(&file, 2, 2), 0x13);
+        assert_eq!(field(&file, 4, 2), 77);
+        assert_eq!(field(&file, 6, 2), crc16(&file[8..77]) as usize);
+        assert_eq!(field(&file, 8, 4), file.len() - 77);
+        assert_eq!(field(&file, 12, 2), crc16(&file[77..]) as usize);
+        assert_eq!(field(&file, 14, 3), 4); // slices
+        assert_eq!(field(&file, 17, 3), 1); // images
+        assert_eq!(field(&file, 20, 1), 0); // ETC1S
+        assert_eq!(field(&file, 21, 2), 1 | 4); // has alpha slices
+        assert_eq!(field(&file, 65, 4), 77); // slice descs
+
+        // codebooks follow the four slice descs
+        let codebooks = 77 + 4 * 23;
+        assert_eq!(field(&file, 39, 2), 7);
+        assert_eq!(field(&file, 41, 4), codebooks);
+        assert_eq!(field(&file, 45, 3), 3);
+        assert_eq!(field(&file, 48, 2), 5);
+        assert_eq!(field(&file, 50, 4), codebooks + 3);
+        assert_eq!(field(&file, 54, 3), 2);
+        assert_eq!(field(&file, 57, 4), codebooks + 5);
+        assert_eq!(field(&file, 61, 4), 1);
+        assert_eq!(file[codebooks..codebooks + 6], [0xE0, 0xE1, 0xE2, 0x50, 0x51, 0x7A]);
+
+        // (level, flags, width, height, data) of each slice
+        let slices: [(usize, usize, usize, usize, &[u8]); 4] = [
+            (0, 0, 8, 4, &level0[..6]),
+            (0, 1, 8, 4, &level0[6..]),
+            (1, 0, 4, 2, &level1[..2]),
+            (1, 1, 4, 2, &level1[2..]),
+        ];
+        for (i, (level, flags, width, height, data)) in slices.into_iter().enumerate() {
+            let desc = 77 + 23 * i;
+            assert_eq!(field(&file, desc, 3), 0);
+            assert_eq!(field(&file, desc + 3, 1), level);
+            assert_eq!(field(&file, desc + 4, 1), flags);
+            assert_eq!(field(&file, desc + 5, 2), width);
+            assert_eq!(field(&file, desc + 7, 2), height);
+            assert_eq!(field(&file, desc + 9, 2), width.div_ceil(4));
+            assert_eq!(field(&file, desc + 11, 2), height.div_ceil(4));
+            let offset = field(&file, desc + 13, 4);
+            let size = field(&file, desc + 17, 4);
+            assert_eq!(&file[offset..offset + size], data);
+            assert_eq!(field(&file, desc + 21, 2), crc16(data) as usize);
+        }
+        assert_eq!(file.len(), codebooks + 6 + 13);
+    }
+
+    #[test]
+    fn basis_file_rejects_truncated_data() {
+        let global = global_data();
+        let level0: Vec<u8> = (1..=10).collect();
+        let level1 = [20u8, 21, 22];
+        let truncated = &global[..global.len() - 1];
+        assert!(basis_file(truncated, &[&level0[..], &level1[..]], 8, 4).is_err());
+        assert!(basis_file(&global, &[&level0[..9], &level1[..]], 8, 4).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use super::{cache_key, fetch_cached, parse_tileset, DiskCache, TileSource, TileSourceError};
 use glam::{DMat4, DVec3, DVec4};
 
 #[derive(Debug, Default, Clone)]
@@ -6,6 +7,9 @@ pub struct BoundingVolume {
     pub x_axis: glam::DVec3, // half
     pub y_axis: glam::DVec3, // half
     pub z_axis: glam::DVec3, // half
+    /// `[west, south, east, north, min height, max height]` if this box was built from a region.
+    /// Regions are given in world coordinates and ignore tile transforms.
+    pub region: Option<[f64; 6]>,
 }
 
 pub fn latlon_to_xyz(lat : f64, lon : f64, h: f64) -> glam::DVec3 {
@@ -57,14 +61,14 @@ impl BoundingVolume {
 
         let mut mesh = three_d::CpuMesh::default();
         mesh.indices = three_d::Indices::U32(indices);
-        mesh.positions = three_d::Positions::F64(
+        mesh.positions = three_d::Positions::F32(
             self.get_edge_points()
                 .iter()
-                .map(|v| three_d::vec3(v.x, v.y, v.z))
+                .map(|v| super::glam_d_vec3_to_three_d(&(*v - self.center)))
                 .collect(),
         );
 
-        crate::lines::LineMesh::new(&ctx3d, &mesh)
+        crate::lines::LineMesh::new_with_origin(&ctx3d, &mesh, self.center)
     }
 
     pub fn fromgeo_str(s: &str) -> Self {
@@ -93,6 +97,73 @@ impl BoundingVolume {
             x_axis: ab,
             y_axis: ac,
             z_axis: orthogonal,
+            region: None,
+        }
+    }
+
+    /// Box around a sphere given by its center and radius.
+    pub fn from_sphere(s: [f64; 4]) -> Self {
+        Self {
+            center: glam::dvec3(s[0], s[1], s[2]),
+            x_axis: glam::DVec3::X * s[3],
+            y_axis: glam::DVec3::Y * s[3],
+            z_axis: glam::DVec3::Z * s[3],
+            region: None,
+        }
+    }
+
+    /// Box in the east-north-up frame of the region centre containing the region
+    /// `[west, south, east, north, min height, max height]` (radians and meters).
+    pub fn from_region(r: [f64; 6]) -> Self {
+        let [west, south, mut east, north, min_h, max_h] = r;
+        if east < west {
+            east += std::f64::consts::TAU;
+        }
+        let lat = (south + north) / 2.;
+        let lon = (west + east) / 2.;
+        let origin = latlon_to_xyz(lat.to_degrees(), lon.to_degrees(), (min_h + max_h) / 2.);
+        let up = glam::dvec3(lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin());
+        let east_axis = glam::dvec3(-lon.sin(), lon.cos(), 0.);
+        let north_axis = up.cross(east_axis);
+
+        // the curved surface bulges between the corners, so the box is fitted to a grid of samples
+        const STEPS: usize = 8;
+        let mut min = glam::DVec3::MAX;
+        let mut max = glam::DVec3::MIN;
+        for i in 0..=STEPS {
+            for j in 0..=STEPS {
+                let lat = south + (north - south) * i as f64 / STEPS as f64;
+                let lon = west + (east - west) * j as f64 / STEPS as f64;
+                for h in [min_h, max_h] {
+                    let p = latlon_to_xyz(lat.to_degrees(), lon.to_degrees(), h) - origin;
+                    let local = glam::dvec3(p.dot(east_axis), p.dot(north_axis), p.dot(up));
+                    min = min.min(local);
+                    max = max.max(local);
+                }
+            }
+        }
+        let mid = (min + max) / 2.;
+        let half = (max - min) / 2.;
+        Self {
+            center: origin + east_axis * mid.x + north_axis * mid.y + up * mid.z,
+            x_axis: east_axis * half.x,
+            y_axis: north_axis * half.y,
+            z_axis: up * half.z,
+            region: Some(r),
+        }
+    }
+
+    /// Applies a tile transform, regions stay untouched.
+    pub fn transform(&self, transform: &DMat4) -> Self {
+        if self.region.is_some() {
+            return self.clone();
+        }
+        Self {
+            center: transform.transform_point3(self.center),
+            x_axis: transform.transform_vector3(self.x_axis),
+            y_axis: transform.transform_vector3(self.y_axis),
+            z_axis: transform.transform_vector3(self.z_axis),
+            region: None,
         }
     }
 
@@ -325,18 +396,31 @@ impl<'de> serde::Deserialize<'de> for BoundingVolume {
         D: serde::Deserializer<'de>,
     {
         #[derive(serde::Deserialize)]
-        struct BoxOnly {
+        struct Helper {
             #[serde(rename = "box")]
-            b: [f64; 12],
+            b: Option<[f64; 12]>,
+            region: Option<[f64; 6]>,
+            sphere: Option<[f64; 4]>,
         }
 
-        let helper = BoxOnly::deserialize(deserializer)?;
-        let arr = helper.b;
+        let helper = Helper::deserialize(deserializer)?;
+        if let Some(region) = helper.region {
+            return Ok(Self::from_region(region));
+        }
+        if let Some(sphere) = helper.sphere {
+            return Ok(Self::from_sphere(sphere));
+        }
+        let Some(arr) = helper.b else {
+            return Err(serde::de::Error::custom(
+                "boundingVolume needs a box, region or sphere",
+            ));
+        };
         Ok(Self {
             center: glam::DVec3::new(arr[0], arr[1], arr[2]),
             x_axis: glam::DVec3::new(arr[3], arr[4], arr[5]),
             y_axis: glam::DVec3::new(arr[6], arr[7], arr[8]),
             z_axis: glam::DVec3::new(arr[9], arr[10], arr[11]),
+            region: None,
             // center: glam::DVec3::new(arr[0], arr[2], -arr[1]),
             // x_axis: glam::DVec3::new(arr[3], arr[5], -arr[4]),
             // y_axis: glam::DVec3::new(arr[6], arr[8], -arr[7]),
@@ -345,7 +429,7 @@ impl<'de> serde::Deserialize<'de> for BoundingVolume {
     }
 }
 
-#[derive(Debug, serde::Deserialize, Default)]
+#[derive(Debug, serde::Deserialize, Default, Clone)]
 pub struct Node {
     #[serde(rename = "boundingVolume")]
     pub bounding: BoundingVolume,
@@ -355,6 +439,30 @@ pub struct Node {
     pub content: Option<Content>,
     #[serde(rename = "geometricError")]
     pub err: f64,
+    /// Inherited from the parent if missing.
+    #[serde(default)]
+    pub refine: Option<Refine>,
+    /// Column major transform from the tile to its parent.
+    #[serde(default)]
+    pub transform: Option<[f64; 16]>,
+    /// Set on the root of an implicit tileset, replaced by the tiles of its root subtree on load.
+    #[serde(default, rename = "implicitTiling")]
+    pub implicit_tiling: Option<super::ImplicitTiling>,
+    /// Set on placeholders whose content uri is a subtree that is not loaded yet.
+    #[serde(skip)]
+    pub subtree: Option<std::sync::Arc<super::SubtreeRequest>>,
+}
+
+/// How the content of the children relates to the content of their parent.
+#[derive(Debug, serde::Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Refine {
+    /// The children are drawn in addition to the parent.
+    #[serde(rename = "ADD", alias = "add")]
+    Add,
+    /// The children are drawn instead of the parent.
+    #[default]
+    #[serde(rename = "REPLACE", alias = "replace")]
+    Replace,
 }
 
 #[derive(Debug, serde::Deserialize, Default)]
@@ -364,21 +472,31 @@ pub struct GLBInfo {
     pub err: f64,
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, serde::Deserialize, Clone)]
 pub struct Content {
+    #[serde(alias = "url")]
     pub uri: String,
 }
 
 impl Node {
-    async fn get_glbs(&self, c: &RestClient, v: &BoundingVolume, glbs: &mut Vec<GLBInfo>) {
-        if self.bounding.intersects(v) {
+    /// Collects the glb contents intersecting `v`, loading the nested tilesets on the way.
+    fn get_glbs<'a>(
+        &'a self,
+        c: &'a RestClient,
+        v: &'a BoundingVolume,
+        glbs: &'a mut Vec<GLBInfo>,
+    ) -> crate::http::BoxFuture<'a, Result<(), TileSourceError>> {
+        Box::pin(async move {
+            if !self.bounding.intersects(v) {
+                return Ok(());
+            }
             if !self.children.is_empty() {
                 for n in self.children.iter() {
-                    n.get_glbs(c, v, glbs);
+                    n.get_glbs(c, v, glbs).await?;
                 }
             } else if let Some(content) = &self.content {
                 if content.uri.contains(".json") {
-                    c.get_node(&content.uri).await.unwrap().get_glbs(c, v, glbs);
+                    c.get_node(&content.uri).await?.get_glbs(c, v, glbs).await?;
                 } else if content.uri.contains(".glb") {
                     glbs.push(GLBInfo {
                         bounding: self.bounding.clone(),
@@ -387,87 +505,168 @@ impl Node {
                     });
                 }
             }
-        }
+            Ok(())
+        })
     }
 }
 
 const URL: &'static str = "https://tile.googleapis.com";
 
+/// [`TileSource`] for the Google Photorealistic 3D Tiles api.
 #[derive(Default)]
 pub struct RestClient {
     pub key: String,
-    pub session: String,
-    pub root: Node,
+    pub session: std::sync::RwLock<String>,
+    pub disk_cache: Option<std::sync::Arc<DiskCache>>,
+    /// Held while the session is renewed, so only one root.json request runs at a time.
+    renewal: futures::lock::Mutex<()>,
 }
 
 impl RestClient {
-    pub async fn new(key : String) -> Result<Self, String> {
-        let mut s = Self {
+    pub fn new(key: String) -> Self {
+        Self {
             key,
-            session: "".into(),
-            root: Node::default(),
-        };
+            session: Default::default(),
+            disk_cache: None,
+            renewal: Default::default(),
+        }
+    }
 
-        s.root = s.get_node("/v1/3dtiles/root.json?").await?;
-
-        let uri = &s
-            .root
-            .children
-            .first()
-            .unwrap()
-            .children
-            .first()
-            .unwrap()
-            .content
-            .as_ref()
-            .unwrap()
-            .uri;
-        s.session = reqwest::Url::parse(&(URL.to_string() + uri))
-            .unwrap()
-            .query_pairs()
-            .filter(|x| x.0 == "session")
-            .last()
-            .unwrap()
-            .1
-            .into();
-        return Ok(s);
+    pub fn with_disk_cache(mut self, disk_cache: std::sync::Arc<DiskCache>) -> Self {
+        self.disk_cache = Some(disk_cache);
+        self
     }
 
-    fn get_url(&self, path: &str) -> reqwest::Url {
-        let mut url = reqwest::Url::parse(&(URL.to_string() + path)).unwrap();
+    /// Builds the request url, replacing any `key` or `session` already contained in `path`.
+    fn get_url(&self, path: &str, session: &str) -> Result<reqwest::Url, TileSourceError> {
+        let url = reqwest::Url::parse(&(URL.to_string() + path))
+            .map_err(|x| TileSourceError::Other(format!("{path}: {x}")))?;
+        let mut url = reqwest::Url::parse(&cache_key(&url))
+            .map_err(|x| TileSourceError::Other(format!("{path}: {x}")))?;
         {
             let mut query = url.query_pairs_mut();
             query.append_pair("key", &self.key);
-            if self.session != "" {
-                query.append_pair("session", &self.session);
+            if session != "" {
+                query.append_pair("session", session);
             }
         }
-        return url;
+        return Ok(url);
     }
 
-    pub async fn get_node(&self, path: &str) -> Result<Node, String>  {
-        let res = crate::http::fetch(&ehttp::Request::get(self.get_url(path)))
-            .await?;
-        #[derive(Debug, serde::Deserialize)]
-        pub struct ApiResponse {
-            pub root: Node,
+    /// Loads root.json and takes over the session it hands out.
+    ///
+    /// The root is always requested from the server, which validates the key and hands
+    /// out a fresh session. The disk cache only keeps a copy for offline mode.
+    async fn fetch_root(&self) -> Result<Node, TileSourceError> {
+        if self.key.is_empty() {
+            return Err(TileSourceError::InvalidKey("no api key".into()));
         }
-        let res: ApiResponse = serde_json::from_slice(&res.bytes).map_err(|x| format!("err: {x}"))?;
-        Ok(res.root)
-    }
+        let url = self.get_url(ROOT, "")?;
+        let cache = self.disk_cache.as_deref();
+        let result = match cache {
+            Some(cache) if cache.is_offline() => fetch_cached(Some(cache), url).await,
+            _ => {
+                let key = cache_key(&url);
+                let result = fetch_cached(None, url).await;
+                if let (Some(cache), Ok(bytes)) = (cache, &result) {
+                    cache.put(&key, bytes);
+                }
+                result
+            }
+        };
+        let bytes = match result {
+            Err(TileSourceError::Http {
+                status: 400 | 401 | 403,
+                message,
+            }) => return Err(TileSourceError::InvalidKey(message)),
+            r => r?,
+        };
+        let mut root = parse_tileset(&bytes)?;
+        root.resolve_uris(ROOT, self);
 
-    pub async fn download(&self, path: &str) -> Vec<u8> {
-        let res = crate::http::fetch(&ehttp::Request::get(self.get_url(path)))
-            .await
-            .unwrap();
+        let session = find_session(&root)
+            .ok_or(TileSourceError::Other("root.json contains no session".into()))?;
+        *self.session.write().unwrap() = session;
+        Ok(root)
+    }
 
-        res.bytes
+    /// Replaces the `expired` session. Requests failing at the same time wait for the first
+    /// renewal and reuse its session instead of fetching root.json themselves.
+    async fn renew_session(&self, expired: &str) -> Result<(), TileSourceError> {
+        let _renewal = self.renewal.lock().await;
+        if *self.session.read().unwrap() != expired {
+            return Ok(());
+        }
+        self.fetch_root().await?;
+        Ok(())
     }
 
-    pub async  fn get_glbs(&self, v: &BoundingVolume) -> Vec<GLBInfo> {
+    /// All glb contents intersecting `v`, fails if any tileset on the way fails to load.
+    pub async fn get_glbs(&self, v: &BoundingVolume) -> Result<Vec<GLBInfo>, TileSourceError> {
         let mut glbs = vec![];
-        self.root.get_glbs(self, v, &mut glbs).await;
-        return glbs;
+        let root = self.get_root().await?;
+        root.get_glbs(self, v, &mut glbs).await?;
+        return Ok(glbs);
+    }
+}
+
+const ROOT: &'static str = "/v1/3dtiles/root.json";
+
+/// The session is handed out as query parameter of the content uris below the root.
+fn find_session(node: &Node) -> Option<String> {
+    if let Some(content) = &node.content {
+        let session = reqwest::Url::parse(&(URL.to_string() + &content.uri))
+            .ok()
+            .and_then(|url| {
+                url.query_pairs()
+                    .find(|x| x.0 == "session")
+                    .map(|x| x.1.to_string())
+            });
+        if session.is_some() {
+            return session;
+        }
+    }
+    node.children.iter().find_map(find_session)
+}
+
+impl TileSource for RestClient {
+    fn get_root(&self) -> crate::http::BoxFuture<'_, Result<Node, TileSourceError>> {
+        Box::pin(self.fetch_root())
+    }
+
+    /// Requests rejected because the session expired renew it once and are repeated.
+    fn download<'a>(
+        &'a self,
+        uri: &'a str,
+    ) -> crate::http::BoxFuture<'a, Result<Vec<u8>, TileSourceError>> {
+        Box::pin(async move {
+            let session = self.session.read().unwrap().clone();
+            let cache = self.disk_cache.as_deref();
+            match fetch_cached(cache, self.get_url(uri, &session)?).await {
+                Err(TileSourceError::Http {
+                    status: 401 | 403 | 410,
+                    ..
+                }) if session != "" => {
+                    self.renew_session(&session).await?;
+                    let session = self.session.read().unwrap().clone();
+                    fetch_cached(cache, self.get_url(uri, &session)?).await
+                }
+                r => r,
+            }
+        })
+    }
+
+    fn resolve_uri(&self, base: &str, uri: &str) -> String {
+        if uri.starts_with('/') {
+            return uri.to_string();
+        }
+        reqwest::Url::parse(&(URL.to_string() + base))
+            .and_then(|b| b.join(uri))
+            .map(|u| match u.query() {
+                Some(q) => format!("{}?{}", u.path(), q),
+                None => u.path().to_string(),
+            })
+            .unwrap_or_else(|_| uri.to_string())
     }
 }
 
@@ -4,9 +4,12 @@ pub struct ViewState {
     pub frustum: Frustum,
     pub planes: [Plane; 6],
     pub position: glam::DVec3,
+    pub direction: glam::DVec3,
     pub viewport_size: glam::DVec2,
     pub culling_volume: CullingVolume,
     pub projection_matrix: glam::DMat4,
+    /// Tiles with a larger screen space error in pixels are refined.
+    pub maximum_screen_space_error: f64,
 }
 
 impl ViewState {
@@ -22,38 +25,66 @@ impl ViewState {
         return -ndc_error * self.viewport_size.y / 2.;
     }
 
-    pub fn does_tile_meet_sse(&self, tile: &Tile) -> bool {
+    pub fn screen_space_error(&self, tile: &Tile) -> f64 {
         let distance = tile
             .bounding
             .compute_distance_squared_to_position(self.position)
             .sqrt();
-        let sse = self
-            .compute_screen_space_error(tile.geometric_error, distance)
-            .abs();
+        self.compute_screen_space_error(tile.geometric_error, distance)
+            .abs()
+    }
+
+    pub fn does_tile_meet_sse(&self, tile: &Tile) -> bool {
+        let sse = self.screen_space_error(tile);
         // println!("sse {}", sse);
-        let maximum_screen_space_error = 16.0;
-        return sse < maximum_screen_space_error;
+        return sse < self.maximum_screen_space_error;
+    }
+
+    /// Higher values load first: large screen space errors close to the screen centre.
+    pub fn load_priority(&self, tile: &Tile) -> f64 {
+        let to_tile = (tile.bounding.center - self.position).normalize_or_zero();
+        let centered = 0.5 + 0.5 * self.direction.dot(to_tile);
+        self.screen_space_error(tile) * centered
     }
 }
 
-pub fn get_view_state(camera: &three_d::Camera) -> ViewState {
-    let position = three_d_vec3_to_glam_d(&camera.position());
-    let frustum = Frustum::from_view_proj_with_origin_far(
-        three_d_to_glam(&(camera.projection() * camera.view())),
-        position,
-    );
-    let s = ViewState {
-        frustum,
-        planes: extract_planes(&three_d_to_glam(&(camera.projection() * camera.view()))),
-        position: three_d_vec3_to_glam_d(&camera.position()),
-        viewport_size: glam::dvec2(
+impl ViewState {
+    /// View state of a perspective camera at `position` looking along `direction`,
+    /// usable without a [`three_d::Camera`] or GL context.
+    pub fn new(
+        position: glam::DVec3,
+        direction: glam::DVec3,
+        view: glam::DMat4,
+        projection: glam::DMat4,
+        viewport_size: glam::DVec2,
+    ) -> Self {
+        let view_projection = projection * view;
+        Self {
+            frustum: Frustum::from_view_proj_with_origin_far(view_projection, position),
+            planes: extract_planes(&view_projection),
+            position,
+            direction,
+            viewport_size,
+            culling_volume: CullingVolume::new_matrix(view_projection),
+            projection_matrix: projection,
+            maximum_screen_space_error: 16.0,
+        }
+    }
+}
+
+/// View state of `camera` at its f64 position `eye`, see [`rtc_camera`]. The view matrix is
+/// rebuilt from `eye` in f64, so culling and screen space errors match the drawn tiles.
+pub fn get_view_state(camera: &three_d::Camera, eye: glam::DVec3) -> ViewState {
+    let direction = three_d_vec3_to_glam_d(&camera.view_direction());
+    let up = three_d_vec3_to_glam_d(&camera.up());
+    ViewState::new(
+        eye,
+        direction,
+        glam::DMat4::look_to_rh(eye, direction, up),
+        three_d_to_glam(camera.projection()),
+        glam::dvec2(
             camera.viewport().width as f64,
             camera.viewport().height as f64,
         ),
-        culling_volume: CullingVolume::new_matrix(three_d_to_glam(
-            &(camera.projection() * camera.view()),
-        )),
-        projection_matrix: three_d_to_glam(&camera.projection()),
-    };
-    return s;
-}
\ No newline at end of file
+    )
+}
@@ -1,5 +1,19 @@
 use three_d::*;
 
+/// Applies the orbit of the camera around the earth centre from `before` to `after` to the
+/// f64 `eye`. The f32 camera position is rounded to decimetres, so `eye` only follows
+/// movements and stays exact while the camera rests.
+pub fn follow_orbit(eye: glam::DVec3, before: Vec3, after: Vec3) -> glam::DVec3 {
+    if before == after {
+        return eye;
+    }
+    let before = crate::maps::three_d_vec3_to_glam_d(&before);
+    let after = crate::maps::three_d_vec3_to_glam_d(&after);
+    glam::DQuat::from_rotation_arc(before.normalize(), after.normalize())
+        * eye
+        * (after.length() / before.length())
+}
+
 pub fn handle_events(
     camera: &mut Camera,
     ctx: &egui::Context,
@@ -4,8 +4,196 @@ use three_d::HasContext;
 pub struct View {
     pub textures: Option<TexturesContainer>,
     pub size: egui::Vec2,
+    /// How the rendered geometry writes depth, see [`DepthMode`].
+    pub depth_mode: DepthMode,
 }
 
+/// Depth encoding of the scene. Everything rendered into one [`View`] has to use the same.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DepthMode {
+    /// The depth of the projection matrix, precision is lost quickly with a large far/near ratio.
+    #[default]
+    Standard,
+    /// `log2(1 + w) / log2(1 + far)`, keeps precision from centimetres up to planetary distances.
+    /// Geometry has to be rendered with [`LogDepth`] materials.
+    Logarithmic,
+}
+
+impl DepthMode {
+    /// `(near, far)` for a camera `height` meters above the ground, seeing up to `horizon` meters.
+    pub fn near_far(&self, height: f32, horizon: f32) -> (f32, f32) {
+        let height = height.max(1.);
+        let far = horizon.max(height * 2.) + 100_000.;
+        let near = match self {
+            DepthMode::Standard => (height * 0.1).clamp(0.5, 100_000.),
+            // log depth does not need the near plane for precision, only to avoid clipping
+            DepthMode::Logarithmic => (height * 0.01).clamp(0.1, 1_000.),
+        };
+        (near, far)
+    }
+}
+
+/// First of the [`three_d::EffectMaterialId`]s used by the materials of this crate.
+/// three-d keeps the ids from 0x8000 up for its own materials, applications should not
+/// use 0x6d00 to 0x6dff.
+pub const MATERIAL_ID_BLOCK: u16 = 0x6d00;
+/// Id of [`LogDepth`], +1 with a texture.
+pub const LOG_DEPTH_MATERIAL_ID: u16 = MATERIAL_ID_BLOCK;
+/// Id of [`crate::maps::TileMaterial`], +1 with a texture, +2 with log depth, +4 with a
+/// compressed texture and +8 per draped image, up to [`crate::maps::MAX_DRAPES`].
+pub const TILE_MATERIAL_ID: u16 = MATERIAL_ID_BLOCK + 0x10;
+
+/// Renames the `main` of a fragment shader to `name`, so a new `main` can call it.
+pub fn rename_main(source: &str, name: &str) -> String {
+    assert!(
+        source.contains("void main()"),
+        "fragment shader without `void main()`"
+    );
+    source.replacen("void main()", &format!("void {name}()"), 1)
+}
+
+/// Wraps the `main` of a fragment shader so it writes logarithmic depth afterwards.
+/// The shader needs the `logDepthFar` uniform, the far plane of the viewer.
+pub fn with_log_depth(source: String) -> String {
+    let source = rename_main(&source, "color_main");
+    format!(
+        "{source}
+uniform float logDepthFar;
+void main() {{
+    color_main();
+    gl_FragDepth = log2(1.0 + 1.0 / gl_FragCoord.w) / log2(1.0 + logDepthFar);
+}}
+"
+    )
+}
+
+/// [`three_d::ColorMaterial`] writing logarithmic depth.
+/// The depth is derived from `gl_FragCoord.w` in the fragment shader, so any geometry works.
+pub struct LogDepth<'a>(pub &'a three_d::ColorMaterial);
+
+impl three_d::Material for LogDepth<'_> {
+    fn id(&self) -> three_d::EffectMaterialId {
+        let texture = self.0.texture.is_some() as u16;
+        three_d::EffectMaterialId(LOG_DEPTH_MATERIAL_ID + texture)
+    }
+
+    fn fragment_shader_source(&self, lights: &[&dyn three_d::Light]) -> String {
+        with_log_depth(self.0.fragment_shader_source(lights))
+    }
+
+    fn use_uniforms(
+        &self,
+        program: &three_d::Program,
+        viewer: &dyn three_d::Viewer,
+        lights: &[&dyn three_d::Light],
+    ) {
+        self.0.use_uniforms(program, viewer, lights);
+        program.use_uniform("logDepthFar", viewer.z_far());
+    }
+
+    fn render_states(&self) -> three_d::RenderStates {
+        self.0.render_states()
+    }
+
+    fn material_type(&self) -> three_d::MaterialType {
+        self.0.material_type()
+    }
+}
+
+/// Renders `geometry` with `material`, wrapped in [`LogDepth`] if `depth_mode` asks for it.
+pub fn render_with_depth_mode(
+    geometry: &dyn three_d::Geometry,
+    material: &three_d::ColorMaterial,
+    depth_mode: DepthMode,
+    viewer: &dyn three_d::Viewer,
+    lights: &[&dyn three_d::Light],
+) {
+    match depth_mode {
+        DepthMode::Standard => geometry.render_with_material(material, viewer, lights),
+        DepthMode::Logarithmic => {
+            geometry.render_with_material(&LogDepth(material), viewer, lights)
+        }
+    }
+}
+
+/// Point of the scene under a pixel, see [`View::pick`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PickResult {
+    /// Earth-centred, earth-fixed position in meters.
+    pub position: glam::DVec3,
+    /// Degrees.
+    pub lat: f64,
+    /// Degrees.
+    pub lon: f64,
+    /// Meters above the WGS84 ellipsoid.
+    pub height: f64,
+    /// Distance from the camera in meters.
+    pub distance: f64,
+}
+
+impl PickResult {
+    /// `eye` is the f64 camera position, see [`crate::maps::rtc_camera`].
+    pub fn new(position: glam::DVec3, eye: glam::DVec3) -> Self {
+        let (lat, lon, height) = crate::maps::xyz_to_latlonele(position);
+        Self {
+            position,
+            lat,
+            lon,
+            height,
+            distance: position.distance(eye),
+        }
+    }
+}
+
+/// Normalized device coordinates of `pos`, relative to the top left corner of the viewport.
+fn pixel_to_ndc(camera: &three_d::Camera, pos: egui::Pos2) -> glam::DVec2 {
+    let viewport = camera.viewport();
+    glam::dvec2(
+        2. * pos.x as f64 / viewport.width as f64 - 1.,
+        1. - 2. * pos.y as f64 / viewport.height as f64,
+    )
+}
+
+/// Inverse of the view matrix of `camera` placed at the f64 `eye`, only the rotation is
+/// taken from the f32 view.
+fn view_to_world(camera: &three_d::Camera, eye: glam::DVec3) -> glam::DMat4 {
+    let mut rotation = crate::maps::three_d_to_glam(camera.view());
+    rotation.w_axis = glam::DVec4::W;
+    glam::DMat4::from_translation(eye) * rotation.transpose()
+}
+
+/// World space ray `(origin, direction)` through `pos`, relative to the top left corner of
+/// the viewport, for `camera` at `eye`. The origin is on the near plane, the direction is
+/// normalized.
+pub fn screen_ray(
+    camera: &three_d::Camera,
+    eye: glam::DVec3,
+    pos: egui::Pos2,
+) -> (glam::DVec3, glam::DVec3) {
+    let ndc = pixel_to_ndc(camera, pos);
+    let inverse = view_to_world(camera, eye)
+        * crate::maps::three_d_to_glam(camera.projection()).inverse();
+    let near = inverse.project_point3(glam::dvec3(ndc.x, ndc.y, -1.));
+    let far = inverse.project_point3(glam::dvec3(ndc.x, ndc.y, 1.));
+    (near, (far - near).normalize())
+}
+
+/// Packs the depth at `pickUv` into RGBA8, which can be read back on all platforms.
+const READ_DEPTH_SHADER: &str = "
+uniform sampler2D depthMap;
+uniform vec2 pickUv;
+layout (location = 0) out vec4 outColor;
+void main() {
+    float depth = texture(depthMap, pickUv).r;
+    if (depth >= 1.0) {
+        outColor = vec4(1.0);
+        return;
+    }
+    vec4 enc = fract(vec4(1.0, 255.0, 65025.0, 16581375.0) * depth);
+    outColor = enc - enc.yzww * vec4(1.0 / 255.0, 1.0 / 255.0, 1.0 / 255.0, 0.0);
+}
+";
+
 #[derive(Clone)]
 pub struct TexturesContainer {
     pub texture: three_d::Texture2D,
@@ -198,6 +386,7 @@ impl Default for View {
         Self {
             textures: None,
             size: egui::Vec2::ZERO,
+            depth_mode: DepthMode::default(),
         }
     }
 }
@@ -240,6 +429,91 @@ impl View {
         }
     }
 
+    /// Depth buffer value of the last [`View::render`] at `pos`, relative to the top left
+    /// corner of the view. `None` outside of the view and where nothing was drawn over a
+    /// clear depth of 1. Stalls until the GPU has finished the frame.
+    pub fn read_depth(&self, context: &three_d::Context, pos: egui::Pos2) -> Option<f32> {
+        let tex = self.textures.as_ref()?;
+        let (width, height) = (tex.depth_texture.width(), tex.depth_texture.height());
+        if pos.x < 0. || pos.y < 0. || pos.x >= width as f32 || pos.y >= height as f32 {
+            return None;
+        }
+        let uv = three_d::vec2(
+            (pos.x.floor() + 0.5) / width as f32,
+            1. - (pos.y.floor() + 0.5) / height as f32,
+        );
+
+        // depth textures can not be read back on the web, so the value is packed into a color
+        let mut target = three_d::Texture2D::new_empty::<[u8; 4]>(
+            context,
+            1,
+            1,
+            three_d::Interpolation::Nearest,
+            three_d::Interpolation::Nearest,
+            None,
+            three_d::Wrapping::ClampToEdge,
+            three_d::Wrapping::ClampToEdge,
+        );
+        let color_target = target.as_color_target(None);
+        color_target
+            .write(|| {
+                let result: Result<(), three_d::CoreError> = Ok(());
+                three_d::apply_effect(
+                    context,
+                    READ_DEPTH_SHADER,
+                    three_d::RenderStates {
+                        depth_test: three_d::DepthTest::Always,
+                        write_mask: three_d::WriteMask::COLOR,
+                        ..Default::default()
+                    },
+                    three_d::Viewport::new_at_origo(1, 1),
+                    |program| {
+                        program.use_depth_texture("depthMap", &tex.depth_texture);
+                        program.use_uniform("pickUv", uv);
+                    },
+                );
+                return result;
+            })
+            .ok()?;
+        let [r, g, b, a] = *color_target.read::<[u8; 4]>().first()?;
+        let depth = r as f64 / 255.
+            + g as f64 / 65025.
+            + b as f64 / 16581375.
+            + a as f64 / 4228250625.;
+        if depth >= 1. {
+            return None;
+        }
+        Some(depth as f32)
+    }
+
+    /// Position of the geometry at `pos`, relative to the top left corner of the view, from
+    /// the depth buffer of the last [`View::render`]. `camera` has to be the one the frame
+    /// was rendered with, including its viewport and near/far planes, `eye` its f64 position.
+    pub fn pick(
+        &self,
+        context: &three_d::Context,
+        camera: &three_d::Camera,
+        eye: glam::DVec3,
+        pos: egui::Pos2,
+    ) -> Option<PickResult> {
+        let depth = self.read_depth(context, pos)? as f64;
+        let ndc = pixel_to_ndc(camera, pos);
+        let inverse_projection = crate::maps::three_d_to_glam(camera.projection()).inverse();
+        let view_position = match self.depth_mode {
+            DepthMode::Standard => {
+                inverse_projection.project_point3(glam::dvec3(ndc.x, ndc.y, depth * 2. - 1.))
+            }
+            DepthMode::Logarithmic => {
+                // invert `with_log_depth` to the clip space w, the distance along the view axis
+                let w = 2f64.powf(depth * (1. + camera.z_far() as f64).log2()) - 1.;
+                let p = inverse_projection.project_point3(glam::dvec3(ndc.x, ndc.y, 1.));
+                p * (w / -p.z)
+            }
+        };
+        let position = view_to_world(camera, eye).transform_point3(view_position);
+        Some(PickResult::new(position, eye))
+    }
+
     pub fn show(&self, ui: &mut egui::Ui) {
         if let Some(tex) = &self.textures {
 